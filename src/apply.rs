@@ -0,0 +1,695 @@
+//! Native apply mode: performs the backup, remove, rename and link steps of the
+//! generated script in-process. Every step is planned in an append-only
+//! journal before anything is touched, and its progress is recorded and
+//! fsynced as it runs, so an interrupted run can be resumed or rolled back
+//! exactly.
+
+use crate::{file_unchanged, os_bytes, FileStamp, HashType};
+use anyhow::{anyhow, Result};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// First line of every journal.
+const JOURNAL_HEADER: &str = "# deduplicate-rs apply journal:";
+
+/// Current journal format.
+const JOURNAL_FORMAT: u32 = 1;
+
+/// What a step does to its file once the file has been backed up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Remove,
+    Rename(PathBuf),
+    // Replace the file with a hard link to the kept copy of its contents
+    HardLink(PathBuf),
+    // Replace the file with a symbolic link with these contents, which
+    // resolve to the kept copy
+    Symlink(PathBuf),
+}
+
+/// One step of the plan: back up `path`, then remove or rename it. The step
+/// only runs while the file still has the size and checksum recorded for it.
+#[derive(Debug, Clone)]
+pub struct Action {
+    pub path: PathBuf,
+    pub backup: PathBuf,
+    pub size: u64,
+    pub checksum: String,
+    pub operation: Operation,
+}
+
+/// How far a planned step got, as recorded in the journal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepState {
+    Planned,
+    Begun,
+    Done,
+    Skipped,
+    Undone,
+}
+
+/// Counts reported at the end of an apply, resume or rollback.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub done: usize,
+    pub skipped: usize,
+    pub undone: usize,
+    // Finished steps that could not be reversed and are left in the journal
+    pub irreversible: usize,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} steps applied, {} skipped, {} rolled back", self.done, self.skipped, self.undone)?;
+        if self.irreversible > 0 {
+            write!(f, ", {} could not be rolled back", self.irreversible)?;
+        }
+        Ok(())
+    }
+}
+
+/// An append-only journal. Each record is a tab-separated line, flushed to
+/// disk before the step it describes moves on.
+struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Create a journal at `path` and write the whole plan to it.
+    fn create(path: &Path, hash_type: HashType, actions: &[Action]) -> Result<Self> {
+        if path.exists() {
+            return Err(anyhow!("Journal already exists: {}", path.display()));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut journal = Journal { file: File::create(path)? };
+        writeln!(journal.file, "{} hash={} format={}", JOURNAL_HEADER, hash_type.key(), JOURNAL_FORMAT)?;
+        for (step, action) in actions.iter().enumerate() {
+            let (operation, to) = match &action.operation {
+                Operation::Remove => ("remove", None),
+                Operation::Rename(to) => ("rename", Some(escape_field(to))),
+                Operation::HardLink(target) => ("hardlink", Some(escape_field(target))),
+                Operation::Symlink(target) => ("symlink", Some(escape_field(target))),
+            };
+            let mut fields = vec![
+                "step".to_string(),
+                step.to_string(),
+                operation.to_string(),
+                action.size.to_string(),
+                action.checksum.clone(),
+                escape_field(&action.path),
+                escape_field(&action.backup),
+            ];
+            fields.extend(to);
+            writeln!(journal.file, "{}", fields.join("\t"))?;
+        }
+        journal.file.sync_all()?;
+
+        Ok(journal)
+    }
+
+    fn append(path: &Path) -> Result<Self> {
+        Ok(Journal { file: OpenOptions::new().append(true).open(path)? })
+    }
+
+    fn record(&mut self, event: &str, step: usize) -> Result<()> {
+        writeln!(self.file, "{}\t{}", event, step)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// A journal read back from disk: the plan, and how far each step got.
+struct JournalState {
+    hash_type: HashType,
+    actions: Vec<Action>,
+    states: Vec<StepState>,
+}
+
+impl JournalState {
+    fn load(path: &Path) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let fields = header.strip_prefix(JOURNAL_HEADER)
+            .ok_or_else(|| anyhow!("Not an apply journal: {}", path.display()))?;
+        let field = |key: &str| {
+            fields.split_whitespace()
+                .find_map(|field| field.strip_prefix(key).and_then(|value| value.strip_prefix('=')))
+        };
+        if field("format").and_then(|value| value.parse().ok()) != Some(JOURNAL_FORMAT) {
+            return Err(anyhow!("Unsupported journal format: {}", path.display()));
+        }
+        let hash_type = field("hash")
+            .ok_or_else(|| anyhow!("Journal does not name its hash: {}", path.display()))?
+            .parse()
+            .map_err(|e: String| anyhow!(e))?;
+
+        let mut actions = Vec::new();
+        let mut states = Vec::new();
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            let malformed = || anyhow!("Malformed journal line {}: {}", number + 2, line);
+            let fields: Vec<&str> = line.split('\t').collect();
+            let step: usize = fields.get(1).and_then(|step| step.parse().ok()).ok_or_else(malformed)?;
+
+            if fields[0] == "step" {
+                if step != actions.len() {
+                    return Err(malformed());
+                }
+                let action = parse_action(&fields).ok_or_else(malformed)?;
+                actions.push(action);
+                states.push(StepState::Planned);
+                continue;
+            }
+
+            let state = states.get_mut(step).ok_or_else(malformed)?;
+            *state = match fields[0] {
+                "begin" => StepState::Begun,
+                "done" => StepState::Done,
+                "skip" => StepState::Skipped,
+                "undo" => StepState::Undone,
+                _ => return Err(malformed()),
+            };
+        }
+
+        Ok(JournalState { hash_type, actions, states })
+    }
+}
+
+fn parse_action(fields: &[&str]) -> Option<Action> {
+    let operation = match (fields.get(2)?, fields.len()) {
+        (&"remove", 7) => Operation::Remove,
+        (&"rename", 8) => Operation::Rename(unescape_field(fields[7])?),
+        (&"hardlink", 8) => Operation::HardLink(unescape_field(fields[7])?),
+        (&"symlink", 8) => Operation::Symlink(unescape_field(fields[7])?),
+        _ => return None,
+    };
+    Some(Action {
+        path: unescape_field(fields[5])?,
+        backup: unescape_field(fields[6])?,
+        size: fields[3].parse().ok()?,
+        checksum: fields[4].to_string(),
+        operation,
+    })
+}
+
+/// Escape a path for a journal field, keeping it byte-exact and free of tabs
+/// and line breaks.
+fn escape_field(path: &Path) -> String {
+    os_bytes::escape(path.as_os_str(), |c, escaped| match c {
+        '\\' => escaped.push_str("\\\\"),
+        '\t' => escaped.push_str("\\t"),
+        '\n' => escaped.push_str("\\n"),
+        '\r' => escaped.push_str("\\r"),
+        c => escaped.push(c),
+    })
+}
+
+fn unescape_field(field: &str) -> Option<PathBuf> {
+    os_bytes::unescape(field).map(PathBuf::from)
+}
+
+/// Plan `actions` in a new journal at `journal_path`, then run them in order.
+pub fn apply(journal_path: &Path, hash_type: HashType, actions: &[Action]) -> Result<Summary> {
+    let mut journal = Journal::create(journal_path, hash_type, actions)?;
+    let mut summary = Summary::default();
+    for (step, action) in actions.iter().enumerate() {
+        run_step(&mut journal, hash_type, step, action, StepState::Planned, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+/// Finish the steps of an interrupted run. A step that was begun is picked
+/// up from wherever it stopped.
+pub fn resume(journal_path: &Path) -> Result<Summary> {
+    let state = JournalState::load(journal_path)?;
+    if state.states.contains(&StepState::Undone) {
+        return Err(anyhow!("{} has been rolled back and cannot be resumed", journal_path.display()));
+    }
+
+    let mut journal = Journal::append(journal_path)?;
+    let mut summary = Summary::default();
+    for (step, (action, &step_state)) in state.actions.iter().zip(&state.states).enumerate() {
+        if matches!(step_state, StepState::Planned | StepState::Begun) {
+            run_step(&mut journal, state.hash_type, step, action, step_state, &mut summary)?;
+        }
+    }
+    Ok(summary)
+}
+
+/// Reverse every step that was begun, newest first: removed and linked files
+/// are copied back from the backup and renames are moved back. Restored files are checked
+/// against their recorded size and checksum. A finished step that cannot be
+/// reversed is reported and left unmarked, so a later rollback can retry it.
+pub fn rollback(journal_path: &Path) -> Result<Summary> {
+    let state = JournalState::load(journal_path)?;
+    let mut journal = Journal::append(journal_path)?;
+    let mut summary = Summary::default();
+
+    for (step, (action, &step_state)) in state.actions.iter().zip(&state.states).enumerate().rev() {
+        if !matches!(step_state, StepState::Begun | StepState::Done) {
+            continue;
+        }
+
+        let restored = match &action.operation {
+            Operation::Remove if !action.path.exists() && action.backup.exists() => {
+                println!("Restoring {}", action.path.display());
+                copy_synced(&action.backup, &action.path)?;
+                true
+            },
+            Operation::Rename(to) if !action.path.exists() && to.exists() => {
+                println!("Moving back {} -> {}", to.display(), action.path.display());
+                fs::rename(to, &action.path)?;
+                true
+            },
+            Operation::HardLink(target) if same_file(&action.path, target) && action.backup.exists() => {
+                println!("Restoring {} in place of its hard link", action.path.display());
+                let temp_path = temp_sibling(&action.path);
+                copy_synced(&action.backup, &temp_path)?;
+                fs::rename(&temp_path, &action.path)?;
+                true
+            },
+            Operation::Symlink(target) if links_to(&action.path, target) && action.backup.exists() => {
+                println!("Restoring {} in place of its symlink", action.path.display());
+                let temp_path = temp_sibling(&action.path);
+                copy_synced(&action.backup, &temp_path)?;
+                fs::rename(&temp_path, &action.path)?;
+                true
+            },
+            // The step stopped before touching the file
+            _ if step_state == StepState::Begun => false,
+            _ => {
+                println!(
+                    "WARNING: cannot roll back step {} for {}: its backup is missing or the path has changed since",
+                    step + 1,
+                    action.path.display()
+                );
+                summary.irreversible += 1;
+                continue;
+            },
+        };
+        if restored && !file_unchanged(state.hash_type, &action.path, action.size, &action.checksum)? {
+            println!("WARNING: restored {} does not match its recorded checksum", action.path.display());
+        }
+
+        journal.record("undo", step)?;
+        if restored {
+            summary.undone += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Run one step. `step_state` is the state the journal left it in; a step
+/// that was begun before an interruption may already be partly done.
+fn run_step(
+    journal: &mut Journal,
+    hash_type: HashType,
+    step: usize,
+    action: &Action,
+    step_state: StepState,
+    summary: &mut Summary,
+) -> Result<()> {
+    if step_state == StepState::Planned {
+        journal.record("begin", step)?;
+    }
+
+    // A symlink made by the step counts as the file, even if it dangles
+    let outcome = if action.path.exists() || action.path.is_symlink() {
+        perform(hash_type, action)?
+    } else if step_state == StepState::Begun && finished(hash_type, action)? {
+        Ok(())
+    } else {
+        Err(format!("{}: no longer exists", action.path.display()))
+    };
+
+    match outcome {
+        Ok(()) => {
+            journal.record("done", step)?;
+            summary.done += 1;
+        },
+        Err(reason) => {
+            println!("WARNING: skipping {}", reason);
+            journal.record("skip", step)?;
+            summary.skipped += 1;
+        },
+    }
+    Ok(())
+}
+
+/// Back up the file and remove or rename it. Returns the reason the step was
+/// skipped if the file changed or the rename target is taken.
+fn perform(hash_type: HashType, action: &Action) -> Result<Result<(), String>> {
+    match &action.operation {
+        Operation::Rename(to) if to.exists() => {
+            return Ok(Err(format!("{}: {} already exists", action.path.display(), to.display())));
+        },
+        Operation::HardLink(target) if same_file(&action.path, target) => {
+            println!("Already linked: {}", action.path.display());
+            return Ok(Ok(()));
+        },
+        Operation::HardLink(target) if !same_metadata(&action.path, target) => {
+            return Ok(Err(format!("{}: permissions, owner or mtime differ from the kept copy {}", action.path.display(), target.display())));
+        },
+        Operation::HardLink(target) if !file_unchanged(hash_type, target, action.size, &action.checksum)? => {
+            return Ok(Err(format!("{}: kept copy {} changed since it was analyzed", action.path.display(), target.display())));
+        },
+        Operation::Symlink(target) if links_to(&action.path, target) => {
+            println!("Already linked: {}", action.path.display());
+            return Ok(Ok(()));
+        },
+        Operation::Symlink(target) => {
+            let kept = symlink_destination(&action.path, target);
+            if !file_unchanged(hash_type, &kept, action.size, &action.checksum)? {
+                return Ok(Err(format!("{}: kept copy {} changed since it was analyzed", action.path.display(), kept.display())));
+            }
+        },
+        _ => {},
+    }
+    if !file_unchanged(hash_type, &action.path, action.size, &action.checksum)? {
+        return Ok(Err(format!("{}: changed since it was analyzed", action.path.display())));
+    }
+
+    if let Some(parent) = action.backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    copy_synced(&action.path, &action.backup)?;
+
+    match &action.operation {
+        Operation::Remove => {
+            println!("Removing {}", action.path.display());
+            fs::remove_file(&action.path)?;
+        },
+        Operation::Rename(to) => {
+            println!("Renaming {} -> {}", action.path.display(), to.display());
+            fs::rename(&action.path, to)?;
+        },
+        Operation::HardLink(target) => {
+            // Link under a temporary name first, so the path never goes missing
+            println!("Linking {} -> {}", action.path.display(), target.display());
+            let temp_path = temp_sibling(&action.path);
+            remove_stale(&temp_path)?;
+            fs::hard_link(target, &temp_path)?;
+            fs::rename(&temp_path, &action.path)?;
+        },
+        Operation::Symlink(target) => {
+            println!("Linking {} -> {}", action.path.display(), target.display());
+            let temp_path = temp_sibling(&action.path);
+            remove_stale(&temp_path)?;
+            symlink(target, &temp_path)?;
+            fs::rename(&temp_path, &action.path)?;
+        },
+    }
+    Ok(Ok(()))
+}
+
+/// Whether a begun step whose file is gone had completed before it was
+/// interrupted: a removal needs an intact backup, a rename its target.
+fn finished(hash_type: HashType, action: &Action) -> Result<bool> {
+    match &action.operation {
+        Operation::Remove => {
+            Ok(action.backup.exists() && file_unchanged(hash_type, &action.backup, action.size, &action.checksum)?)
+        },
+        Operation::Rename(to) => Ok(to.exists()),
+        // Linking replaces the file in one rename, so it is never missing
+        Operation::HardLink(_) | Operation::Symlink(_) => Ok(false),
+    }
+}
+
+/// Whether two paths name the same file. Never true off Unix, where files
+/// have no device and inode.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => {
+            let id = FileStamp::from_metadata(&a).file_id();
+            id.is_some() && id == FileStamp::from_metadata(&b).file_id()
+        },
+        _ => false,
+    }
+}
+
+/// Whether two files have the same permissions, owner and mtime, so a hard
+/// link to one can stand in for the other.
+pub fn same_metadata(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        #[cfg(unix)]
+        (Ok(a), Ok(b)) => {
+            use std::os::unix::fs::MetadataExt;
+            (a.mode(), a.uid(), a.gid(), a.mtime(), a.mtime_nsec())
+                == (b.mode(), b.uid(), b.gid(), b.mtime(), b.mtime_nsec())
+        },
+        #[cfg(not(unix))]
+        (Ok(a), Ok(b)) => a.permissions() == b.permissions() && a.modified().ok() == b.modified().ok(),
+        _ => false,
+    }
+}
+
+/// Whether `path` is a symbolic link with the contents `target`.
+fn links_to(path: &Path, target: &Path) -> bool {
+    fs::read_link(path).is_ok_and(|contents| contents == target)
+}
+
+/// The path a symbolic link at `link` with the contents `target` resolves
+/// to; a relative target is relative to the link's directory.
+fn symlink_destination(link: &Path, target: &Path) -> PathBuf {
+    link.parent().unwrap_or(Path::new("")).join(target)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+/// Remove a temporary file left behind by an interrupted step.
+fn remove_stale(temp_path: &Path) -> Result<()> {
+    match fs::remove_file(temp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A name next to `path` to build its replacement under before renaming it
+/// into place.
+pub fn temp_sibling(path: &Path) -> PathBuf {
+    let mut temp_path = OsString::from(path.as_os_str());
+    temp_path.push(".dedup-tmp");
+    PathBuf::from(temp_path)
+}
+
+/// Copy `from` to `to`, keeping its permissions and mtime, and flush the
+/// copy to disk.
+fn copy_synced(from: &Path, to: &Path) -> Result<()> {
+    fs::copy(from, to)?;
+    // Opened read-only: the copy may have taken read-only permissions
+    let copy = File::open(to)?;
+    copy.set_modified(fs::metadata(from)?.modified()?)?;
+    copy.sync_all()?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::hash_file;
+    use crate::test_dir::TestDir;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    const HASH: HashType = HashType::XXH3;
+
+    /// Write `contents` to `dir/name` and plan `operation` for it, backed up
+    /// under `dir/backup`.
+    fn planned(dir: &Path, name: &str, contents: &[u8], operation: Operation) -> Action {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        let (checksum, size) = hash_file(HASH, &path).unwrap();
+        Action { path, backup: dir.join("backup").join(name), size, checksum, operation }
+    }
+
+    /// Journal a plan whose steps were all begun, as a run interrupted
+    /// during its first step leaves it.
+    fn interrupted_journal(dir: &Path, actions: &[Action]) -> PathBuf {
+        let journal_path = dir.join("backup").join("journal.txt");
+        let mut journal = Journal::create(&journal_path, HASH, actions).unwrap();
+        journal.record("begin", 0).unwrap();
+        journal_path
+    }
+
+    #[test]
+    fn journal_fields_round_trip() {
+        let names: &[&[u8]] = &[b"plain.mp4", b"tab\there.mp4", b"new\nline\r.mp4", b"back\\slash\\t.mp4", b"caf\xe9 \xff.mp4"];
+        let dir = TestDir::new("apply-fields");
+        let actions: Vec<Action> = names.iter()
+            .map(|name| Action {
+                path: dir.join(OsStr::from_bytes(name)),
+                backup: dir.join("backup").join(OsStr::from_bytes(name)),
+                size: 42,
+                checksum: "0123456789abcdef".to_string(),
+                operation: Operation::Rename(dir.join(OsStr::from_bytes(name)).with_extension("mkv")),
+            })
+            .collect();
+        let journal_path = dir.join("journal.txt");
+        Journal::create(&journal_path, HASH, &actions).unwrap();
+
+        let contents = fs::read_to_string(&journal_path).unwrap();
+        assert_eq!(contents.lines().count(), names.len() + 1);
+        let state = JournalState::load(&journal_path).unwrap();
+        for (loaded, action) in state.actions.iter().zip(&actions) {
+            assert_eq!(loaded.path, action.path);
+            assert_eq!(loaded.backup, action.backup);
+            assert_eq!(loaded.operation, action.operation);
+        }
+        assert!(state.states.iter().all(|&state| state == StepState::Planned));
+    }
+
+    #[test]
+    fn resumes_remove_interrupted_after_backup() {
+        let dir = TestDir::new("apply-remove");
+        let action = planned(&dir, "copy.mp4", b"duplicate contents", Operation::Remove);
+        let journal_path = interrupted_journal(&dir, std::slice::from_ref(&action));
+        fs::create_dir_all(action.backup.parent().unwrap()).unwrap();
+        copy_synced(&action.path, &action.backup).unwrap();
+
+        let summary = resume(&journal_path).unwrap();
+        assert_eq!((summary.done, summary.skipped), (1, 0));
+        assert!(!action.path.exists());
+        assert_eq!(fs::read(&action.backup).unwrap(), b"duplicate contents");
+    }
+
+    #[test]
+    fn resumes_remove_interrupted_after_it_ran() {
+        let dir = TestDir::new("apply-removed");
+        let action = planned(&dir, "copy.mp4", b"duplicate contents", Operation::Remove);
+        let journal_path = interrupted_journal(&dir, std::slice::from_ref(&action));
+        fs::create_dir_all(action.backup.parent().unwrap()).unwrap();
+        copy_synced(&action.path, &action.backup).unwrap();
+        fs::remove_file(&action.path).unwrap();
+
+        let summary = resume(&journal_path).unwrap();
+        assert_eq!((summary.done, summary.skipped), (1, 0));
+    }
+
+    #[test]
+    fn resumes_rename_interrupted_after_it_ran() {
+        let dir = TestDir::new("apply-rename");
+        let action = planned(&dir, "clip_1.mp4", b"renamed contents", Operation::Rename(dir.join("clip.mp4")));
+        let journal_path = interrupted_journal(&dir, std::slice::from_ref(&action));
+        fs::create_dir_all(action.backup.parent().unwrap()).unwrap();
+        copy_synced(&action.path, &action.backup).unwrap();
+        fs::rename(&action.path, dir.join("clip.mp4")).unwrap();
+
+        let summary = resume(&journal_path).unwrap();
+        assert_eq!((summary.done, summary.skipped), (1, 0));
+        assert_eq!(fs::read(dir.join("clip.mp4")).unwrap(), b"renamed contents");
+    }
+
+    #[test]
+    fn rolls_back_after_resume() {
+        let dir = TestDir::new("apply-rollback");
+        fs::create_dir(dir.join("kept")).unwrap();
+        let keep = dir.join("kept").join("clip.mp4");
+        fs::write(&keep, b"shared contents").unwrap();
+        let actions = vec![
+            planned(&dir, "removed.mp4", b"removed contents", Operation::Remove),
+            planned(&dir, "clip_1.mp4", b"renamed contents", Operation::Rename(dir.join("clip.mp4"))),
+            planned(&dir, "hard.mp4", b"shared contents", Operation::HardLink(keep.clone())),
+            planned(&dir, "soft.mp4", b"shared contents", Operation::Symlink(PathBuf::from("kept/clip.mp4"))),
+        ];
+        // A hard link only replaces a copy with the kept file's metadata
+        let mtime = fs::metadata(&keep).unwrap().modified().unwrap();
+        File::open(dir.join("hard.mp4")).unwrap().set_modified(mtime).unwrap();
+        let journal_path = interrupted_journal(&dir, &actions);
+
+        let summary = resume(&journal_path).unwrap();
+        assert_eq!((summary.done, summary.skipped), (4, 0));
+        assert!(!dir.join("removed.mp4").exists());
+        assert!(same_file(&dir.join("hard.mp4"), &keep));
+        assert_eq!(fs::read_link(dir.join("soft.mp4")).unwrap(), Path::new("kept/clip.mp4"));
+
+        let summary = rollback(&journal_path).unwrap();
+        assert_eq!(summary.undone, 4);
+        for action in &actions {
+            assert!(!action.path.is_symlink() && !same_file(&action.path, &keep), "{}", action.path.display());
+            assert!(file_unchanged(HASH, &action.path, action.size, &action.checksum).unwrap());
+        }
+        assert!(!dir.join("clip.mp4").exists());
+        assert!(resume(&journal_path).is_err());
+    }
+
+    #[test]
+    fn reports_steps_it_cannot_roll_back() {
+        let dir = TestDir::new("apply-irreversible");
+        let action = planned(&dir, "copy.mp4", b"duplicate contents", Operation::Remove);
+        let journal_path = dir.join("backup").join("journal.txt");
+        let summary = apply(&journal_path, HASH, std::slice::from_ref(&action)).unwrap();
+        assert_eq!((summary.done, summary.skipped), (1, 0));
+        let backup = fs::read(&action.backup).unwrap();
+        fs::remove_file(&action.backup).unwrap();
+
+        let summary = rollback(&journal_path).unwrap();
+        assert_eq!((summary.undone, summary.irreversible), (0, 1));
+        assert!(!action.path.exists());
+        assert_eq!(JournalState::load(&journal_path).unwrap().states, [StepState::Done]);
+
+        // Once the backup is back, the step can still be rolled back
+        fs::write(&action.backup, backup).unwrap();
+        let summary = rollback(&journal_path).unwrap();
+        assert_eq!((summary.undone, summary.irreversible), (1, 0));
+        assert!(file_unchanged(HASH, &action.path, action.size, &action.checksum).unwrap());
+    }
+
+    #[test]
+    fn skips_hard_link_when_metadata_differs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("apply-metadata");
+        let keep = dir.join("kept.mp4");
+        fs::write(&keep, b"shared contents").unwrap();
+        let action = planned(&dir, "copy.mp4", b"shared contents", Operation::HardLink(keep.clone()));
+        let mtime = fs::metadata(&keep).unwrap().modified().unwrap();
+        File::open(&action.path).unwrap().set_modified(mtime).unwrap();
+        fs::set_permissions(&action.path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let summary = apply(&dir.join("backup").join("journal.txt"), HASH, std::slice::from_ref(&action)).unwrap();
+        assert_eq!((summary.done, summary.skipped), (0, 1));
+        assert!(!same_file(&action.path, &keep));
+        assert_eq!(fs::metadata(&action.path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn rejects_malformed_journals() {
+        let header = format!("{} hash=xxh3 format={}", JOURNAL_HEADER, JOURNAL_FORMAT);
+        let step = "step\t0\tremove\t3\tabc\t/a.mp4\t/backup/a.mp4";
+        let cases = [
+            ("missing header", step.to_string()),
+            ("unknown format", format!("{} hash=xxh3 format=99\n{}", JOURNAL_HEADER, step)),
+            ("unknown hash", format!("{} hash=md5 format={}\n{}", JOURNAL_HEADER, JOURNAL_FORMAT, step)),
+            ("steps out of order", format!("{}\nstep\t1\tremove\t3\tabc\t/a.mp4\t/backup/a.mp4", header)),
+            ("unknown operation", format!("{}\nstep\t0\tshred\t3\tabc\t/a.mp4\t/backup/a.mp4", header)),
+            ("rename without target", format!("{}\nstep\t0\trename\t3\tabc\t/a.mp4\t/backup/a.mp4", header)),
+            ("bad size", format!("{}\nstep\t0\tremove\tbig\tabc\t/a.mp4\t/backup/a.mp4", header)),
+            ("bad escape", format!("{}\nstep\t0\tremove\t3\tabc\t/a\\q.mp4\t/backup/a.mp4", header)),
+            ("unknown event", format!("{}\n{}\nfinish\t0", header, step)),
+            ("event for an unplanned step", format!("{}\n{}\ndone\t1", header, step)),
+            ("event without a step", format!("{}\n{}\ndone", header, step)),
+        ];
+
+        let dir = TestDir::new("apply-malformed");
+        let journal_path = dir.join("journal.txt");
+        for (name, contents) in cases {
+            fs::write(&journal_path, contents).unwrap();
+            assert!(JournalState::load(&journal_path).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the per-directory ignore file, read with gitignore semantics.
+pub const IGNORE_FILENAME: &str = ".dedupignore";
+
+/// Decides which paths under a root are left out of the scan: `--exclude`
+/// patterns and `.dedupignore` files use gitignore semantics, `--include`
+/// globs restrict which files are scanned at all.
+pub struct PathFilter {
+    root: PathBuf,
+    excludes: Gitignore,
+    includes: Option<GlobSet>,
+    ignore_files: HashMap<PathBuf, Gitignore>,
+}
+
+impl PathFilter {
+    pub fn new(root: &Path, excludes: &[String], includes: &[String]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in excludes {
+            builder.add_line(None, pattern)
+                .map_err(|e| anyhow!("Invalid --exclude pattern {}: {}", pattern, e))?;
+        }
+        let excludes = builder.build()?;
+
+        let includes = if includes.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in includes {
+                let glob = Glob::new(pattern)
+                    .map_err(|e| anyhow!("Invalid --include pattern {}: {}", pattern, e))?;
+                builder.add(glob);
+            }
+            Some(builder.build()?)
+        };
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            excludes,
+            includes,
+            ignore_files: HashMap::new(),
+        })
+    }
+
+    /// Read `dir/.dedupignore`, if there is one. Must be called for each
+    /// directory before any path below it is checked.
+    pub fn load_ignore_file(&mut self, dir: &Path) -> Result<()> {
+        let ignore_path = dir.join(IGNORE_FILENAME);
+        if !ignore_path.is_file() {
+            return Ok(());
+        }
+
+        let (matcher, error) = Gitignore::new(&ignore_path);
+        if let Some(e) = error {
+            println!("WARNING: {}: {}", ignore_path.display(), e);
+        }
+        self.ignore_files.insert(dir.to_path_buf(), matcher);
+        Ok(())
+    }
+
+    /// The rule that excludes `path`, if any, described for the report.
+    pub fn excluded_by(&self, path: &Path, is_dir: bool) -> Option<String> {
+        // The closest .dedupignore wins, and a `!pattern` re-includes against
+        // the ignore files further up; the command-line rules still apply
+        for dir in path.ancestors().skip(1) {
+            if let Some(matcher) = self.ignore_files.get(dir) {
+                match matcher.matched(path, is_dir) {
+                    Match::Ignore(glob) => {
+                        let source = glob.from().unwrap_or(matcher.path());
+                        return Some(format!("{}: {}", source.display(), glob.original()));
+                    },
+                    Match::Whitelist(_) => break,
+                    Match::None => {},
+                }
+            }
+            if dir == self.root {
+                break;
+            }
+        }
+
+        if let Match::Ignore(glob) = self.excludes.matched(path, is_dir) {
+            return Some(format!("--exclude {}", glob.original()));
+        }
+
+        if let Some(includes) = &self.includes {
+            let relative = path.strip_prefix(&self.root).unwrap_or(path);
+            if !is_dir && !includes.is_match(relative) {
+                return Some("not matched by --include".to_string());
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::fs;
+
+    /// A filter over `root` that has read the `.dedupignore` files written
+    /// into the given subdirectories.
+    fn filter(root: &Path, excludes: &[&str], includes: &[&str], ignore_files: &[(&str, &str)]) -> PathFilter {
+        let excludes: Vec<String> = excludes.iter().map(|pattern| pattern.to_string()).collect();
+        let includes: Vec<String> = includes.iter().map(|pattern| pattern.to_string()).collect();
+        let mut filter = PathFilter::new(root, &excludes, &includes).unwrap();
+        for (dir, contents) in ignore_files {
+            let dir = root.join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(IGNORE_FILENAME), contents).unwrap();
+            filter.load_ignore_file(&dir).unwrap();
+        }
+        filter
+    }
+
+    #[test]
+    fn nearest_ignore_file_wins() {
+        let root = TestDir::new("filter-nearest");
+        let filter = filter(&root, &[], &[], &[("", "*.mkv\n"), ("keep", "!*.mkv\n"), ("other", "*.tmp\n")]);
+
+        let rule = filter.excluded_by(&root.join("a.mkv"), false).unwrap();
+        assert!(rule.ends_with(&format!("{}: *.mkv", IGNORE_FILENAME)), "{}", rule);
+        assert_eq!(filter.excluded_by(&root.join("keep").join("b.mkv"), false), None);
+        assert_eq!(filter.excluded_by(&root.join("keep").join("deep").join("c.mkv"), false), None);
+        // A nearer file without a matching rule defers to the ones above it
+        assert!(filter.excluded_by(&root.join("other").join("d.mkv"), false).is_some());
+        assert!(filter.excluded_by(&root.join("other").join("e.tmp"), false).is_some());
+        assert_eq!(filter.excluded_by(&root.join("other").join("f.mp4"), false), None);
+    }
+
+    #[test]
+    fn command_line_rules_apply_to_reincluded_paths() {
+        let root = TestDir::new("filter-whitelist");
+        let filter = filter(&root, &["*_sample.mkv"], &["keep/*"], &[("", "*.mkv\n"), ("keep", "!*.mkv\n")]);
+
+        assert_eq!(filter.excluded_by(&root.join("keep").join("clip.mkv"), false), None);
+        assert_eq!(
+            filter.excluded_by(&root.join("keep").join("clip_sample.mkv"), false),
+            Some("--exclude *_sample.mkv".to_string())
+        );
+        assert_eq!(
+            filter.excluded_by(&root.join("other").join("clip.mp4"), false),
+            Some("not matched by --include".to_string())
+        );
+    }
+
+    #[test]
+    fn directory_patterns_only_match_directories() {
+        let root = TestDir::new("filter-dirs");
+        let filter = filter(&root, &["Samples/"], &[], &[("", "Extras/\n")]);
+
+        assert_eq!(filter.excluded_by(&root.join("Samples"), true), Some("--exclude Samples/".to_string()));
+        assert_eq!(filter.excluded_by(&root.join("Samples"), false), None);
+        assert!(filter.excluded_by(&root.join("Movies").join("Extras"), true).is_some());
+        assert_eq!(filter.excluded_by(&root.join("Movies").join("Extras"), false), None);
+    }
+
+    #[test]
+    fn includes_match_paths_relative_to_the_root() {
+        let root = TestDir::new("filter-include");
+        let filter = filter(&root, &[], &["Movies/*.mkv"], &[]);
+
+        assert_eq!(filter.excluded_by(&root.join("Movies").join("a.mkv"), false), None);
+        assert!(filter.excluded_by(&root.join("TV").join("Movies").join("b.mkv"), false).is_some());
+        assert!(filter.excluded_by(&root.join("Movies").join("c.mp4"), false).is_some());
+        // Directories are still walked, so files below them can match
+        assert_eq!(filter.excluded_by(&root.join("TV"), true), None);
+    }
+}
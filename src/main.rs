@@ -2,28 +2,46 @@ use anyhow::{anyhow, Result};
 use bytesize::ByteSize;
 use chrono::Local;
 use clap::Parser;
-use twox_hash::xxh3::hash128;
 use humantime::format_duration;
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 use std::time::{Duration, Instant};
 use sysinfo::{System, SystemExt, ProcessExt};
 use walkdir::WalkDir;
-use twox_hash::xxh3::hash64;
-use blake3;
-use t1ha::t1ha0;
+use xxhash_rust::xxh3::Xxh3;
+
+mod apply;
+mod filter;
+mod os_bytes;
+mod reflink;
+mod shell;
+mod sniff;
+#[cfg(test)]
+mod test_dir;
+
+use apply::{same_metadata, temp_sibling, Action, Operation};
+use filter::{PathFilter, IGNORE_FILENAME};
+use reflink::{ShareMode, Shared};
+use sniff::{sniff_container, Container};
+use t1ha::T1ha2Hasher;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum HashType {
     XXH3,      // Fast, non-cryptographic 64-bit hash
     XXH3_128,  // Fast, non-cryptographic 128-bit hash
-    Xxh3Simd,  // XXH3 with SIMD optimizations (vectorized)
+    Xxh3Simd,  // Reference XXH3 with SIMD acceleration, matches `xxhsum -H3`
     Blake3,    // Cryptographically secure hash
     T1HA,      // Fast Positive Hash
+    Sha256,    // Cryptographic hash, checkable with sha256sum
 }
 
 impl std::fmt::Display for HashType {
@@ -34,6 +52,32 @@ impl std::fmt::Display for HashType {
             HashType::Xxh3Simd => write!(f, "XXH3-SIMD"),
             HashType::Blake3 => write!(f, "Blake3"),
             HashType::T1HA => write!(f, "T1HA"),
+            HashType::Sha256 => write!(f, "SHA-256"),
+        }
+    }
+}
+
+impl HashType {
+    /// Lower-case name used on the command line and in the checksum database.
+    fn key(&self) -> &'static str {
+        match self {
+            HashType::XXH3 => "xxh3",
+            HashType::XXH3_128 => "xxh3-128",
+            HashType::Xxh3Simd => "xxh3-simd",
+            HashType::Blake3 => "blake3",
+            HashType::T1HA => "t1ha",
+            HashType::Sha256 => "sha256",
+        }
+    }
+    
+    /// First database format holding this hash type's current digests; rows
+    /// from older databases cannot be reused.
+    fn digest_format(&self) -> u32 {
+        match self {
+            HashType::Xxh3Simd => 2,
+            HashType::T1HA => 3,
+            HashType::XXH3 | HashType::XXH3_128 => 4,
+            _ => 1,
         }
     }
 }
@@ -48,6 +92,7 @@ impl std::str::FromStr for HashType {
             "xxh3-simd" | "simd" => Ok(HashType::Xxh3Simd),
             "blake3" => Ok(HashType::Blake3),
             "t1ha" => Ok(HashType::T1HA),
+            "sha256" | "sha-256" => Ok(HashType::Sha256),
             _ => Err(format!("Unknown hash type: {}", s)),
         }
     }
@@ -56,38 +101,701 @@ impl std::str::FromStr for HashType {
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Media File Deduplication Tool")]
 struct Args {
-    #[arg(short, long)]
-    filepath: Option<PathBuf>,
+    #[arg(short, long, num_args = 1.., help = "Directories to scan, highest keep priority first (defaults to the current directory)")]
+    filepath: Vec<PathBuf>,
     
-    #[arg(short = 'a', long, default_value = "xxh3", help = "Hash algorithm to use (xxh3, xxh3-128, xxh3-simd, blake3, t1ha)")]
+    #[arg(short = 'a', long, default_value = "xxh3", help = "Hash algorithm to use (xxh3, xxh3-128, xxh3-simd, blake3, t1ha, sha256)")]
     hash: HashType,
+    
+    #[arg(long, default_value_t = 64, help = "KiB hashed from the start of same-size files before a full hash (0 disables the partial stage)")]
+    partial_head_kib: u64,
+    
+    #[arg(long, default_value_t = 64, help = "KiB hashed from the end of same-size files before a full hash")]
+    partial_tail_kib: u64,
+    
+    #[arg(short, long, help = "Number of files hashed concurrently (defaults to the number of CPUs)")]
+    jobs: Option<usize>,
+    
+    #[arg(long, help = "Ignore cached checksums and rehash every candidate")]
+    rehash: bool,
+    
+    #[arg(long, help = "Compare duplicate group members byte by byte before scripting removals")]
+    verify: bool,
+    
+    #[arg(long, help = "Re-hash members of colliding groups with a cryptographic hash (blake3, sha256) before calling them duplicates")]
+    confirm_hash: Option<HashType>,
+    
+    #[arg(short = 't', long, value_delimiter = ',', default_value = "video", help = "Media type groups to scan (video, image, audio, all)")]
+    types: Vec<MediaType>,
+    
+    #[arg(long = "ext", value_delimiter = ',', help = "Additional file extensions to scan")]
+    include_ext: Vec<String>,
+    
+    #[arg(long, value_delimiter = ',', help = "File extensions to leave out of the scan")]
+    exclude_ext: Vec<String>,
+    
+    #[arg(long, help = "Also detect media files by their container signature, whatever their extension")]
+    sniff: bool,
+    
+    #[arg(long, help = "Only scan files whose path relative to the root matches this glob (repeatable)")]
+    include: Vec<String>,
+    
+    #[arg(long, help = "Leave out paths matching this gitignore-style pattern, e.g. @eaDir or Samples/ (repeatable)")]
+    exclude: Vec<String>,
+    
+    #[arg(long, help = "Follow symbolic links to files and directories")]
+    follow_symlinks: bool,
+    
+    #[arg(long, help = "Do not descend into directories on other filesystems")]
+    one_file_system: bool,
+    
+    #[arg(long, help = "Maximum directory depth to scan below each root (0 scans only the root itself)")]
+    max_depth: Option<usize>,
+    
+    #[arg(long, help = "Skip hidden files and directories (names starting with a dot)")]
+    skip_hidden: bool,
+    
+    #[arg(long, help = "Skip files smaller than this, e.g. 10MB or 512KiB")]
+    min_size: Option<ByteSize>,
+    
+    #[arg(long, help = "Skip files larger than this, e.g. 4GiB")]
+    max_size: Option<ByteSize>,
+    
+    #[arg(long, default_value = "none", help = "How cross-directory duplicates are handled: none (listed as commented-out removals), hardlink or symlink (replaced with hard or symbolic links to the kept copy)")]
+    link_mode: LinkMode,
+    
+    #[arg(long, default_value = "relative", help = "Whether --link-mode symlink links point at the kept copy by a relative or an absolute path")]
+    symlink_style: SymlinkStyle,
+    
+    #[arg(long, help = "Report symbolic links below the --filepath directories whose target is missing, then exit")]
+    check_links: bool,
+    
+    #[arg(long, value_name = "MODE", help = "With --apply, share the blocks of every duplicate left in place with its kept copy, on Btrfs or XFS: dedupe (FIDEDUPERANGE, the kernel compares the data first) or clone (FICLONE reflink after comparing the files byte by byte)")]
+    share_extents: Option<ShareMode>,
+    
+    #[arg(long, help = "Perform the script's backup, remove and rename steps directly, recording each in a journal in the backup directory")]
+    apply: bool,
+    
+    #[arg(long, value_name = "JOURNAL", conflicts_with = "rollback", help = "Finish an interrupted --apply run from its journal, then exit")]
+    resume: Option<PathBuf>,
+    
+    #[arg(long, value_name = "JOURNAL", help = "Reverse every step an --apply run began, from its journal, then exit")]
+    rollback: Option<PathBuf>,
+    
+    #[arg(long, requires_all = ["expect_size", "expect_checksum"], help = "Check that this file still has the expected size and --hash checksum, then exit (used by the generated script)")]
+    check_file: Option<PathBuf>,
+    
+    #[arg(long, requires = "check_file", help = "Size in bytes the --check-file must have")]
+    expect_size: Option<u64>,
+    
+    #[arg(long, requires = "check_file", help = "Checksum the --check-file must have")]
+    expect_checksum: Option<String>,
+    
+    #[arg(long, value_name = "KEPT", requires = "check_file", help = "Also check that the --check-file has the same permissions, owner and mtime as this file")]
+    same_metadata_as: Option<PathBuf>,
 }
 
 const VIDEO_FORMATS: [&str; 11] = [
     "mp4", "flv", "mkv", "avi", "mov", "wmv", "webm", "m4v", "mpg", "mpeg", "ts",
 ];
 
+const IMAGE_FORMATS: [&str; 19] = [
+    "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "heic", "heif", "webp",
+    "raw", "dng", "cr2", "cr3", "nef", "arw", "orf", "rw2", "raf",
+];
+
+const AUDIO_FORMATS: [&str; 11] = [
+    "mp3", "flac", "m4a", "aac", "ogg", "opus", "wav", "wma", "aiff", "ape", "alac",
+];
+
+/// Named groups of file extensions selectable with `--types`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MediaType {
+    Video,
+    Image,
+    Audio,
+    All,
+}
+
+impl MediaType {
+    fn extensions(&self) -> Vec<&'static str> {
+        match self {
+            MediaType::Video => VIDEO_FORMATS.to_vec(),
+            MediaType::Image => IMAGE_FORMATS.to_vec(),
+            MediaType::Audio => AUDIO_FORMATS.to_vec(),
+            MediaType::All => [&VIDEO_FORMATS[..], &IMAGE_FORMATS[..], &AUDIO_FORMATS[..]].concat(),
+        }
+    }
+}
+
+impl std::str::FromStr for MediaType {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "video" => Ok(MediaType::Video),
+            "image" => Ok(MediaType::Image),
+            "audio" => Ok(MediaType::Audio),
+            "all" => Ok(MediaType::All),
+            _ => Err(format!("Unknown media type: {}", s)),
+        }
+    }
+}
+
+/// What the script does with cross-directory duplicates, selected with
+/// `--link-mode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkMode {
+    None,      // List them as commented-out removals
+    Hardlink,  // Replace each redundant copy with a hard link to the kept one
+    Symlink,   // Replace each redundant copy with a symbolic link to the kept one
+}
+
+impl std::str::FromStr for LinkMode {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(LinkMode::None),
+            "hardlink" | "hard" => Ok(LinkMode::Hardlink),
+            "symlink" | "soft" => Ok(LinkMode::Symlink),
+            _ => Err(format!("Unknown link mode: {}", s)),
+        }
+    }
+}
+
+/// How `--link-mode symlink` points a link at the kept copy, selected with
+/// `--symlink-style`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SymlinkStyle {
+    Relative,  // Path from the link's directory, surviving a move of the whole tree
+    Absolute,  // Full path of the kept copy
+}
+
+impl std::str::FromStr for SymlinkStyle {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "relative" => Ok(SymlinkStyle::Relative),
+            "absolute" => Ok(SymlinkStyle::Absolute),
+            _ => Err(format!("Unknown symlink style: {}", s)),
+        }
+    }
+}
+
+/// The path leading from the directory `from` to `to`, both absolute.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+/// Print every symbolic link below `roots` whose target does not exist, and
+/// return how many there are.
+fn report_dangling_symlinks(roots: &[PathBuf]) -> usize {
+    let mut dangling = 0;
+    for root in roots {
+        for entry in WalkDir::new(root).sort_by_file_name().into_iter().filter_map(Result::ok) {
+            if entry.file_type().is_symlink() && !entry.path().exists() {
+                let target = fs::read_link(entry.path()).unwrap_or_default();
+                println!("WARNING: dangling symlink: {} -> {}", entry.path().display(), target.display());
+                dangling += 1;
+            }
+        }
+    }
+    println!("Dangling symlinks: {}", dangling);
+    dangling
+}
+
+/// Normalise an extension given on the command line (`.JPG` -> `jpg`).
+fn normalize_extension(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+/// Size of the read buffer used when streaming file contents through a hasher.
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// First line of every checksum database.
+const DATABASE_HEADER: &str = "# deduplicate-rs checksum database:";
+
+/// Current checksum database format. Format 2 replaced the chunk-XOR
+/// XXH3-SIMD values with reference XXH3 digests, format 3 the block-chained
+/// T1HA values with streaming t1ha2 digests, and format 4 the pre-release
+/// XXH3 and XXH3-128 values with reference ones.
+const DATABASE_FORMAT: u32 = 4;
+
+/// Incremental hasher state for every `HashType`, so file contents can be
+/// fed through in fixed-size pieces instead of being loaded into memory.
+enum StreamingHasher {
+    XXH3(Box<Xxh3>),
+    XXH3_128(Box<Xxh3>),
+    Xxh3Simd(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    T1HA(T1ha2Hasher),
+    Sha256(Sha256),
+}
+
+impl StreamingHasher {
+    fn new(hash_type: HashType) -> Self {
+        match hash_type {
+            HashType::XXH3 => StreamingHasher::XXH3(Box::new(Xxh3::new())),
+            HashType::XXH3_128 => StreamingHasher::XXH3_128(Box::new(Xxh3::new())),
+            HashType::Xxh3Simd => StreamingHasher::Xxh3Simd(Box::new(Xxh3::new())),
+            HashType::Blake3 => StreamingHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashType::T1HA => StreamingHasher::T1HA(T1ha2Hasher::with_seeds(0, 0)),
+            HashType::Sha256 => StreamingHasher::Sha256(Sha256::new()),
+        }
+    }
+    
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamingHasher::XXH3(hasher) => hasher.update(data),
+            StreamingHasher::XXH3_128(hasher) => hasher.update(data),
+            StreamingHasher::Xxh3Simd(hasher) => hasher.update(data),
+            StreamingHasher::Blake3(hasher) => {
+                hasher.update(data);
+            },
+            StreamingHasher::T1HA(hasher) => hasher.update(data),
+            StreamingHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+    
+    fn finish(self) -> String {
+        match self {
+            StreamingHasher::XXH3(hasher) => format!("{:016x}", hasher.digest()),
+            StreamingHasher::XXH3_128(hasher) => format!("{:032x}", hasher.digest128()),
+            StreamingHasher::Xxh3Simd(hasher) => format!("{:016x}", hasher.digest()),
+            StreamingHasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            StreamingHasher::T1HA(mut hasher) => format!("{:016x}", hasher.finish()),
+            StreamingHasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Fill `buffer` from `reader`, stopping early only at end of file. Feeding
+/// the hashers whole blocks keeps digests independent of short reads.
+fn read_block<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Hash a file by streaming it through a fixed-size buffer, returning the
+/// digest and the number of bytes read. Peak memory does not depend on the
+/// size of the file.
+fn hash_file(hash_type: HashType, file_path: &Path) -> Result<(String, u64)> {
+    let mut file = File::open(file_path)?;
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut hasher = StreamingHasher::new(hash_type);
+    let mut bytes_read = 0u64;
+    
+    loop {
+        let len = read_block(&mut file, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer[..len]);
+        bytes_read += len as u64;
+    }
+    
+    Ok((hasher.finish(), bytes_read))
+}
+
+/// Hash the first `head` and last `tail` bytes of a file of length `size`.
+/// Returns the digest, the number of bytes read and whether the two ranges
+/// covered the whole file, in which case the digest equals `hash_file`'s.
+fn hash_file_partial(
+    hash_type: HashType,
+    file_path: &Path,
+    size: u64,
+    head: u64,
+    tail: u64,
+) -> Result<(String, u64, bool)> {
+    if head.saturating_add(tail) >= size {
+        let (digest, bytes_read) = hash_file(hash_type, file_path)?;
+        return Ok((digest, bytes_read, true));
+    }
+    
+    let mut file = File::open(file_path)?;
+    let mut hasher = StreamingHasher::new(hash_type);
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE.min((head.max(tail)) as usize)];
+    let mut bytes_read = 0u64;
+    
+    for (offset, len) in [(0, head), (size - tail, tail)] {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let want = remaining.min(buffer.len() as u64) as usize;
+            let got = read_block(&mut file, &mut buffer[..want])?;
+            if got == 0 {
+                break;
+            }
+            hasher.update(&buffer[..got]);
+            bytes_read += got as u64;
+            remaining -= got as u64;
+        }
+    }
+    
+    Ok((hasher.finish(), bytes_read, false))
+}
+
+/// Compare two files byte by byte.
+fn files_identical(a: &Path, b: &Path) -> Result<bool> {
+    let (mut file_a, mut file_b) = (File::open(a)?, File::open(b)?);
+    if file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
+    }
+    
+    let mut buffer_a = vec![0u8; HASH_BUFFER_SIZE];
+    let mut buffer_b = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let len_a = read_block(&mut file_a, &mut buffer_a)?;
+        let len_b = read_block(&mut file_b, &mut buffer_b)?;
+        if buffer_a[..len_a] != buffer_b[..len_b] {
+            return Ok(false);
+        }
+        if len_a == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Share the extents of `file_path` with `keep_file`. Clone mode compares the
+/// pair byte by byte first, as the kernel does not compare the data for it.
+fn share_pair(mode: ShareMode, keep_file: &Path, file_path: &Path) -> Result<Shared> {
+    if mode == ShareMode::Clone && !files_identical(keep_file, file_path)? {
+        return Ok(Shared::Differs);
+    }
+    Ok(reflink::share_extents(mode, keep_file, file_path)?)
+}
+
+/// Whether `file_path` still has the size and checksum recorded for it. The
+/// size is compared first, so a file that grew or shrank is not read.
+fn file_unchanged(hash_type: HashType, file_path: &Path, size: u64, checksum: &str) -> Result<bool> {
+    let current_size = match fs::metadata(file_path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("{}: no longer exists", file_path.display());
+            return Ok(false);
+        },
+        Err(e) => return Err(e.into()),
+    };
+    if current_size != size {
+        eprintln!("{}: size is {} bytes, expected {}", file_path.display(), current_size, size);
+        return Ok(false);
+    }
+    
+    let (current_checksum, _) = hash_file(hash_type, file_path)?;
+    if current_checksum != checksum {
+        eprintln!("{}: {} checksum is {}, expected {}", file_path.display(), hash_type, current_checksum, checksum);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Whether `file_path` has the same permissions, owner and mtime as
+/// `keep_file`, so a hard link to `keep_file` can replace it.
+fn metadata_matches(file_path: &Path, keep_file: &Path) -> bool {
+    if same_metadata(file_path, keep_file) {
+        return true;
+    }
+    eprintln!("{}: permissions, owner or mtime differ from {}", file_path.display(), keep_file.display());
+    false
+}
+
+/// Format a checksum database row the way coreutils' `sha256sum` does, so
+/// that a SHA-256 database can be checked with `sha256sum -c`. Paths holding
+/// a backslash or line break are escaped and the row marked with a leading
+/// backslash. Bytes that are not valid UTF-8 are escaped as `\xNN`, which
+/// keeps such paths byte-exact but is an extension coreutils does not read.
+fn format_checksum_line(checksum: &str, path: &Path) -> String {
+    if let Some(path) = path.to_str().filter(|path| !path.contains(['\\', '\n', '\r'])) {
+        return format!("{}  {}", checksum, path);
+    }
+    
+    let escaped = os_bytes::escape(path.as_os_str(), |c, escaped| match c {
+        '\\' => escaped.push_str("\\\\"),
+        '\n' => escaped.push_str("\\n"),
+        '\r' => escaped.push_str("\\r"),
+        c => escaped.push(c),
+    });
+    format!("\\{}  {}", checksum, escaped)
+}
+
+/// Parse a row written by `format_checksum_line`.
+fn parse_checksum_line(line: &str) -> Option<(String, PathBuf)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (checksum, path) = line.split_once("  ")?;
+    if !escaped {
+        return Some((checksum.to_string(), PathBuf::from(path)));
+    }
+    
+    Some((checksum.to_string(), PathBuf::from(os_bytes::unescape(path)?)))
+}
+
+/// File metadata recorded next to each checksum. A cached checksum is only
+/// reused while all of these still match the file on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    mtime_secs: i64,
+    mtime_nanos: i64,
+    dev: u64,
+    ino: u64,
+}
+
+impl FileStamp {
+    #[cfg(unix)]
+    fn from_metadata(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            size: metadata.len(),
+            mtime_secs: metadata.mtime(),
+            mtime_nanos: metadata.mtime_nsec(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+    
+    #[cfg(not(unix))]
+    fn from_metadata(metadata: &fs::Metadata) -> Self {
+        let mtime = metadata.modified().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs() as i64,
+            mtime_nanos: mtime.subsec_nanos() as i64,
+            dev: 0,
+            ino: 0,
+        }
+    }
+    
+    /// Device and inode of the file itself, shared by all of its hard links.
+    /// Not available off Unix.
+    fn file_id(&self) -> Option<(u64, u64)> {
+        (self.ino != 0).then_some((self.dev, self.ino))
+    }
+}
+
+impl std::fmt::Display for FileStamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "size={} mtime={}.{:09} dev={} ino={}",
+            self.size, self.mtime_secs, self.mtime_nanos, self.dev, self.ino
+        )
+    }
+}
+
+impl std::str::FromStr for FileStamp {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stamp = FileStamp { size: 0, mtime_secs: 0, mtime_nanos: 0, dev: 0, ino: 0 };
+        let mut seen = 0;
+        
+        for field in s.split_whitespace() {
+            let (key, value) = field.split_once('=')
+                .ok_or_else(|| format!("Malformed metadata field: {}", field))?;
+            let bad_value = |_| format!("Malformed metadata value: {}", field);
+            match key {
+                "size" => stamp.size = value.parse().map_err(bad_value)?,
+                "mtime" => {
+                    let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+                    stamp.mtime_secs = secs.parse().map_err(bad_value)?;
+                    stamp.mtime_nanos = nanos.parse().map_err(bad_value)?;
+                },
+                "dev" => stamp.dev = value.parse().map_err(bad_value)?,
+                "ino" => stamp.ino = value.parse().map_err(bad_value)?,
+                _ => continue,
+            }
+            seen += 1;
+        }
+        
+        if seen < 4 {
+            return Err(format!("Incomplete metadata: {}", s));
+        }
+        Ok(stamp)
+    }
+}
+
+/// A checksum database row together with the metadata it was computed from.
+/// In two-tier mode the row also carries the confirming digest.
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    stamp: FileStamp,
+    checksum: String,
+    confirm: Option<(HashType, String)>,
+}
+
+impl CacheEntry {
+    /// The comment line written ahead of the row, e.g.
+    /// `# size=1024 mtime=1700000000.000000000 dev=2049 ino=12 blake3=<hex>`.
+    fn meta_line(&self) -> String {
+        match &self.confirm {
+            Some((confirm_type, digest)) => format!("# {} {}={}", self.stamp, confirm_type.key(), digest),
+            None => format!("# {}", self.stamp),
+        }
+    }
+    
+    /// Parse a comment line written by `meta_line`.
+    fn parse_meta_line(meta: &str) -> Option<(FileStamp, Option<(HashType, String)>)> {
+        let stamp = meta.parse().ok()?;
+        let confirm = meta.split_whitespace()
+            .filter_map(|field| field.split_once('='))
+            .find_map(|(key, value)| match key.parse::<HashType>() {
+                Ok(confirm_type) => Some((confirm_type, value.to_string())),
+                Err(_) => None,
+            });
+        Some((stamp, confirm))
+    }
+    
+    fn confirm_digest(&self, confirm_type: HashType) -> Option<String> {
+        self.confirm.as_ref()
+            .filter(|(cached_type, _)| *cached_type == confirm_type)
+            .map(|(_, digest)| digest.clone())
+    }
+}
+
+/// A directory the walk found media files in, with those files in name order.
+struct IndexedDir {
+    path: PathBuf,
+    files: Vec<IndexedFile>,
+}
+
+/// A media file recorded by the directory walk.
+struct IndexedFile {
+    path: PathBuf,
+    stamp: FileStamp,
+}
 
 struct MediaDeduplicator {
-    root_path: PathBuf,
-    script_dir: PathBuf,
+    // Scan roots in priority order; duplicates keep the copy under the earliest root
+    roots: Vec<PathBuf>,
     checksum_db_path: PathBuf,
     destructive_script_path: PathBuf,
+    undo_script_path: PathBuf,
+    backup_dir: PathBuf,
+    
+    // Steps the script performs unconditionally, carried out in-process by
+    // --apply
+    actions: Vec<Action>,
+    apply: bool,
     
-    checksum_to_file: HashMap<String, String>,
-    checksum_to_files: HashMap<String, Vec<String>>,
+    // Script blocks reversing each destructive step, in the order the steps
+    // are written
+    undo_steps: Vec<String>,
+    
+    // Paths the within-directory section removes, which later sections
+    // leave alone
+    within_dir_removals: HashSet<PathBuf>,
+    // Kept copies that symlinks point at, which the filename cleanup leaves
+    // under their current name
+    symlink_targets: HashSet<PathBuf>,
+    
+    checksum_to_files: HashMap<String, Vec<PathBuf>>,
     basename_map: HashSet<String>,
-    dir_dupes: HashMap<String, Vec<String>>,
-    cross_dir_dupes: HashSet<String>,
+    dir_dupes: BTreeMap<PathBuf, BTreeSet<String>>,
+    cross_dir_dupes: BTreeSet<String>,
     
     total_files: usize,
     unique_files: usize,
+    size_unique_files: usize,
+    partial_unique_files: usize,
     same_dir_dupes: usize,
     cross_dir_dupes_count: usize,
     rename_candidates: usize,
+    cached_files: usize,
+    pruned_rows: usize,
+    
+    // Groups whose checksums matched but whose contents did not
+    hash_collisions: Vec<(String, Vec<Vec<PathBuf>>)>,
+    verify: bool,
+    
+    // Two-tier mode: confirming digests of colliding files, keyed by path
+    confirm_hash: Option<HashType>,
+    confirm_digests: HashMap<PathBuf, String>,
+    
+    // Checksums from earlier runs, keyed by path
+    cache: HashMap<PathBuf, CacheEntry>,
+    rehash: bool,
+    
+    // Lower-case extensions of the files being scanned
+    extensions: BTreeSet<String>,
+    sniff: bool,
+    extension_mismatches: Vec<(PathBuf, Container)>,
+    
+    // --include/--exclude and .dedupignore rules for each root, with how
+    // many paths each rule excluded
+    path_filters: Vec<PathFilter>,
+    exclusion_counts: BTreeMap<String, usize>,
+    
+    // Media files found by the single directory walk, which every later
+    // phase works from
+    file_index: Vec<IndexedDir>,
+    
+    // Walker settings
+    follow_symlinks: bool,
+    one_file_system: bool,
+    max_depth: Option<usize>,
+    skip_hidden: bool,
+    
+    // Symlink loops found by the walk as (link, ancestor it leads back to), and
+    // followed links left out because their target is scanned already
+    symlink_loops: Vec<(PathBuf, PathBuf)>,
+    skipped_symlinks: Vec<(PathBuf, PathBuf)>,
+    
+    // Size limits applied before hashing; zero-length files are listed on
+    // their own rather than grouped as duplicates of each other
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    size_filtered: usize,
+    empty_files: Vec<PathBuf>,
+    
+    // Further paths of hard-linked files, keyed by the path that is hashed
+    // and scripted for them
+    hard_links: BTreeMap<PathBuf, Vec<PathBuf>>,
+    
+    // Cross-directory duplicates are replaced with links in this mode; copies
+    // that cannot be linked are listed as (copy, kept file, reason)
+    link_mode: LinkMode,
+    symlink_style: SymlinkStyle,
+    link_fallbacks: Vec<(PathBuf, PathBuf, &'static str)>,
+    
+    // Duplicates whose extents were shared with the kept copy, and the
+    // bytes shared; copies the kernel found different or could not share
+    // are listed as (copy, kept file)
+    share_extents: Option<ShareMode>,
+    shared_files: usize,
+    shared_bytes: u64,
+    share_differs: Vec<(PathBuf, PathBuf)>,
+    share_fallbacks: Vec<(PathBuf, PathBuf, String)>,
     
     // Hash configuration
     hash_type: HashType,
+    partial_head_bytes: u64,
+    partial_tail_bytes: u64,
+    jobs: usize,
     
     // Performance metrics
     start_time: Instant,
@@ -108,28 +816,84 @@ impl MediaDeduplicator {
             HashType::Xxh3Simd => "xxh3_simdsum.txt",
             HashType::Blake3 => "blake3sum.txt",
             HashType::T1HA => "t1hasum.txt",
+            HashType::Sha256 => "sha256sum.txt",
         };
         
         Ok(Self {
-            root_path: current_dir.clone(),
-            script_dir: current_dir.clone(),
+            roots: vec![current_dir.clone()],
             checksum_db_path: current_dir.join(db_name),
             destructive_script_path: current_dir.join("potentially-destructive-remove.sh"),
+            undo_script_path: current_dir.join("undo.sh"),
+            backup_dir: current_dir.join(format!("backup_{}", Local::now().format("%Y%m%d_%H%M%S"))),
+            actions: Vec::new(),
+            apply: false,
+            undo_steps: Vec::new(),
+            within_dir_removals: HashSet::new(),
+            symlink_targets: HashSet::new(),
             
-            checksum_to_file: HashMap::new(),
             checksum_to_files: HashMap::new(),
             basename_map: HashSet::new(),
-            dir_dupes: HashMap::new(),
-            cross_dir_dupes: HashSet::new(),
+            dir_dupes: BTreeMap::new(),
+            cross_dir_dupes: BTreeSet::new(),
             
             total_files: 0,
             unique_files: 0,
+            size_unique_files: 0,
+            partial_unique_files: 0,
             same_dir_dupes: 0,
             cross_dir_dupes_count: 0,
             rename_candidates: 0,
+            cached_files: 0,
+            pruned_rows: 0,
+            
+            hash_collisions: Vec::new(),
+            verify: false,
+            
+            confirm_hash: None,
+            confirm_digests: HashMap::new(),
+            
+            cache: HashMap::new(),
+            rehash: false,
+            
+            extensions: VIDEO_FORMATS.iter().map(|ext| ext.to_string()).collect(),
+            sniff: false,
+            extension_mismatches: Vec::new(),
+            
+            path_filters: vec![PathFilter::new(&current_dir, &[], &[])?],
+            exclusion_counts: BTreeMap::new(),
+            
+            file_index: Vec::new(),
+            
+            follow_symlinks: false,
+            one_file_system: false,
+            max_depth: None,
+            skip_hidden: false,
+            
+            symlink_loops: Vec::new(),
+            skipped_symlinks: Vec::new(),
+            
+            min_size: None,
+            max_size: None,
+            size_filtered: 0,
+            empty_files: Vec::new(),
+            
+            hard_links: BTreeMap::new(),
+            
+            link_mode: LinkMode::None,
+            symlink_style: SymlinkStyle::Relative,
+            link_fallbacks: Vec::new(),
+            
+            share_extents: None,
+            shared_files: 0,
+            shared_bytes: 0,
+            share_differs: Vec::new(),
+            share_fallbacks: Vec::new(),
             
             // Initialize hash type
             hash_type,
+            partial_head_bytes: 64 * 1024,
+            partial_tail_bytes: 64 * 1024,
+            jobs: 1,
             
             // Initialize performance metrics
             start_time: Instant::now(),
@@ -141,90 +905,300 @@ impl MediaDeduplicator {
     }
     
     fn run(&mut self, args: Args) -> Result<()> {
-        if let Some(dir) = args.filepath {
-            self.root_path = fs::canonicalize(dir)?;
+        if !args.filepath.is_empty() {
+            self.roots = args.filepath.iter()
+                .map(fs::canonicalize)
+                .collect::<Result<_, _>>()?;
+        }
+        for (i, root) in self.roots.iter().enumerate() {
+            if let Some(other) = self.roots[..i].iter().find(|other| root.starts_with(other) || other.starts_with(root)) {
+                return Err(anyhow!("Root paths overlap: {} and {}", other.display(), root.display()));
+            }
+        }
+        
+        self.partial_head_bytes = args.partial_head_kib.saturating_mul(1024);
+        self.partial_tail_bytes = args.partial_tail_kib.saturating_mul(1024);
+        self.jobs = args.jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1);
+        self.rehash = args.rehash;
+        self.apply = args.apply;
+        self.link_mode = args.link_mode;
+        self.symlink_style = args.symlink_style;
+        self.verify = args.verify;
+        self.share_extents = args.share_extents;
+        if self.share_extents.is_some() && !self.apply {
+            return Err(anyhow!("--share-extents changes files in place and requires --apply"));
+        }
+        self.confirm_hash = args.confirm_hash;
+        if let Some(confirm_type) = self.confirm_hash {
+            if !matches!(confirm_type, HashType::Blake3 | HashType::Sha256) {
+                return Err(anyhow!("--confirm-hash must be a cryptographic hash (blake3, sha256), not {}", confirm_type));
+            }
+            if confirm_type == self.hash_type {
+                return Err(anyhow!("--confirm-hash must differ from --hash"));
+            }
         }
         
-        println!("Working directory: {}", self.root_path.display());
+        self.extensions = args.types.iter()
+            .flat_map(|media_type| media_type.extensions())
+            .map(normalize_extension)
+            .chain(args.include_ext.iter().map(|ext| normalize_extension(ext)))
+            .collect();
+        for ext in &args.exclude_ext {
+            self.extensions.remove(&normalize_extension(ext));
+        }
+        if self.extensions.is_empty() {
+            return Err(anyhow!("No file extensions left to scan"));
+        }
+        self.sniff = args.sniff;
+        self.path_filters = self.roots.iter()
+            .map(|root| PathFilter::new(root, &args.exclude, &args.include))
+            .collect::<Result<_>>()?;
+        self.follow_symlinks = args.follow_symlinks;
+        self.one_file_system = args.one_file_system;
+        self.max_depth = args.max_depth;
+        self.skip_hidden = args.skip_hidden;
+        self.min_size = args.min_size.map(|size| size.as_u64());
+        self.max_size = args.max_size.map(|size| size.as_u64());
+        if let (Some(min_size), Some(max_size)) = (self.min_size, self.max_size) {
+            if min_size > max_size {
+                return Err(anyhow!("--min-size must not exceed --max-size"));
+            }
+        }
+        
+        if self.roots.len() == 1 {
+            println!("Working directory: {}", self.roots[0].display());
+        } else {
+            for (i, root) in self.roots.iter().enumerate() {
+                println!("Root {} (priority {}): {}", self.root_label(i), i + 1, root.display());
+            }
+        }
+        println!(
+            "Scanning extensions: {}",
+            self.extensions.iter().cloned().collect::<Vec<_>>().join(", ")
+        );
         
         if !self.checksum_db_path.exists() {
             println!("Checksum database not found at {}", self.checksum_db_path.display());
             println!("Creating new database file...");
-            File::create(&self.checksum_db_path)?;
+            self.create_database(&self.checksum_db_path)?;
         }
         
-        let dirs_to_process = self.find_media_dirs()?;
-        println!("Found {} directories with media files", dirs_to_process.len());
+        self.build_file_index()?;
+        println!("Found {} directories with media files", self.file_index.len());
         
         self.init_destructive_script()?;
         
         self.load_database()?;
         
         println!("First pass: collecting file information...");
-        self.process_all_directories(&dirs_to_process)?;
+        self.process_all_directories()?;
+        
+        if let Some(confirm_type) = self.confirm_hash {
+            println!("\nConfirming duplicate groups with {}...", confirm_type);
+            self.confirm_duplicate_groups()?;
+        }
+        if self.verify {
+            println!("\nVerifying duplicate groups byte by byte...");
+            self.verify_duplicate_groups()?;
+        }
+        self.classify_duplicates();
         
         println!("\nSecond pass: analyzing duplicates and preparing actions...");
         self.analyze_within_directory_duplicates()?;
         self.analyze_cross_directory_duplicates()?;
-        self.analyze_rename_candidates(&dirs_to_process)?;
+        self.analyze_rename_candidates()?;
+        if self.link_mode == LinkMode::Symlink {
+            self.write_dangling_link_check()?;
+        }
+        self.finish_undo_script()?;
         
         #[cfg(unix)]
-        {
+        for script_path in [&self.destructive_script_path, &self.undo_script_path] {
             use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(&self.destructive_script_path)?.permissions();
+            let mut perms = fs::metadata(script_path)?.permissions();
             perms.set_mode(0o755);
-            fs::set_permissions(&self.destructive_script_path, perms)?;
+            fs::set_permissions(script_path, perms)?;
+        }
+        
+        // Before the apply steps, which may rename kept copies
+        if let Some(mode) = self.share_extents {
+            println!("\nSharing extents of duplicates with {}...", mode);
+            self.share_duplicate_extents(mode)?;
         }
         
         self.display_results();
         
+        if self.apply {
+            let journal_path = self.backup_dir.join("journal.txt");
+            println!();
+            println!("Applying {} steps (journal: {})", self.actions.len(), journal_path.display());
+            let summary = apply::apply(&journal_path, self.hash_type, &self.actions)?;
+            println!("{}", summary);
+            println!("To reverse them, run: deduplicate-rs --rollback {}", shell::quote(&journal_path));
+            if self.link_mode == LinkMode::Symlink {
+                report_dangling_symlinks(&self.roots);
+            }
+        }
+        
         Ok(())
     }
     
-    fn find_media_dirs(&self) -> Result<Vec<PathBuf>> {
-        println!("Identifying directories containing media files...");
-        let mut dirs = vec![self.root_path.clone()];
+    /// Walk each root once, recording every media file together with its
+    /// metadata. Nothing after this reads a directory again.
+    fn build_file_index(&mut self) -> Result<()> {
+        println!("Indexing media files...");
         
-        for entry in WalkDir::new(&self.root_path)
-            .min_depth(1)
-            .into_iter()
-            .filter_map(Result::ok)
-            .filter(|e| e.file_type().is_dir())
-        {
-            let dir_path = entry.path();
-            
-            let has_media = VIDEO_FORMATS.iter().any(|&format| {
-                dir_path.read_dir().map_or(false, |entries| {
-                    entries
-                        .filter_map(Result::ok)
-                        .any(|e| {
-                            e.file_type().map_or(false, |ft| ft.is_file())
-                                && e.path().extension().map_or(false, |ext| 
-                                    ext.to_string_lossy().to_lowercase() == format)
-                        })
-                })
-            });
+        // Canonical targets of the symlinks followed so far
+        let mut symlink_targets: HashSet<PathBuf> = HashSet::new();
+        
+        for (root_index, root) in self.roots.clone().into_iter().enumerate() {
+            let mut dir_slots: HashMap<PathBuf, usize> = HashMap::new();
+            let mut walker = WalkDir::new(&root)
+                .follow_links(self.follow_symlinks)
+                .same_file_system(self.one_file_system)
+                .sort_by_file_name();
+            // walkdir puts the root's own files at depth 1, so a limit of N
+            // directory levels reads files down to depth N + 1
+            if let Some(max_depth) = self.max_depth {
+                walker = walker.max_depth(max_depth.saturating_add(1));
+            }
+            let mut walker = walker.into_iter();
             
-            if has_media {
-                dirs.push(dir_path.to_path_buf());
+            // Directories are visited before their contents, so each one's
+            // .dedupignore is loaded before anything below it is checked
+            while let Some(entry) = walker.next() {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        // walkdir reports a loop instead of descending into it
+                        match (e.path(), e.loop_ancestor()) {
+                            (Some(link), Some(ancestor)) => {
+                                self.symlink_loops.push((link.to_path_buf(), ancestor.to_path_buf()));
+                            },
+                            _ => println!("WARNING: {}", e),
+                        }
+                        continue;
+                    },
+                };
+                let path = entry.path();
+                let is_dir = entry.file_type().is_dir();
+                
+                if entry.depth() > 0 && self.skip_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+                    if is_dir {
+                        walker.skip_current_dir();
+                    }
+                    continue;
+                }
+                
+                // A followed link whose target lies inside a root, or was reached
+                // through another link, would be scanned a second time
+                if self.follow_symlinks && entry.depth() > 0 && entry.path_is_symlink() {
+                    let target = fs::canonicalize(path)?;
+                    let scanned = self.roots.iter().any(|root| target.starts_with(root));
+                    if scanned || !symlink_targets.insert(target.clone()) {
+                        self.skipped_symlinks.push((path.to_path_buf(), target.clone()));
+                        if is_dir {
+                            walker.skip_current_dir();
+                        }
+                        continue;
+                    }
+                }
+                
+                if is_dir {
+                    if entry.depth() > 0 {
+                        if let Some(rule) = self.path_filters[root_index].excluded_by(path, true) {
+                            *self.exclusion_counts.entry(rule).or_default() += 1;
+                            walker.skip_current_dir();
+                            continue;
+                        }
+                    }
+                    self.path_filters[root_index].load_ignore_file(path)?;
+                    dir_slots.insert(path.to_path_buf(), self.file_index.len());
+                    self.file_index.push(IndexedDir { path: path.to_path_buf(), files: Vec::new() });
+                    continue;
+                }
+                
+                if !entry.file_type().is_file() || entry.file_name() == IGNORE_FILENAME {
+                    continue;
+                }
+                if !self.select_media_file(path, root_index) {
+                    continue;
+                }
+                
+                let stamp = FileStamp::from_metadata(&entry.metadata()?);
+                if stamp.size == 0 {
+                    self.empty_files.push(path.to_path_buf());
+                    continue;
+                }
+                if self.min_size.is_some_and(|min_size| stamp.size < min_size)
+                    || self.max_size.is_some_and(|max_size| stamp.size > max_size)
+                {
+                    self.size_filtered += 1;
+                    continue;
+                }
+                
+                let slot = path.parent().and_then(|parent| dir_slots.get(parent));
+                if let Some(&slot) = slot {
+                    self.file_index[slot].files.push(IndexedFile { path: path.to_path_buf(), stamp });
+                }
             }
         }
         
-        Ok(dirs)
+        self.file_index.retain(|dir| !dir.files.is_empty());
+        self.extension_mismatches.sort_by(|a, b| a.0.cmp(&b.0));
+        
+        Ok(())
+    }
+    
+    /// Whether the walk should record `path`: it has a scanned extension or,
+    /// with --sniff, a selected container, and no filter rule excludes it.
+    /// A file that cannot be read is not sniffed.
+    fn select_media_file(&mut self, path: &Path, root_index: usize) -> bool {
+        let extension_matches = self.has_media_extension(path);
+        if let Some(rule) = self.path_filters[root_index].excluded_by(path, false) {
+            // Only count files that would otherwise have been candidates
+            if extension_matches || self.sniff {
+                *self.exclusion_counts.entry(rule).or_default() += 1;
+            }
+            return false;
+        }
+        
+        if !self.sniff {
+            return extension_matches;
+        }
+        
+        match sniff_container(path) {
+            Ok(Some(container)) => {
+                let ext = path.extension()
+                    .map(|ext| ext.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                if !container.extensions().contains(&ext.as_str()) {
+                    self.extension_mismatches.push((path.to_path_buf(), container));
+                }
+                extension_matches || self.container_selected(container)
+            },
+            Ok(None) => extension_matches,
+            Err(e) => {
+                println!("WARNING: could not sniff {}: {}", path.display(), e);
+                extension_matches
+            },
+        }
     }
     
     fn init_destructive_script(&self) -> Result<()> {
         let mut file = File::create(&self.destructive_script_path)?;
         
         writeln!(file, "#!/usr/bin/env bash")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "# WARNING: This script contains potentially destructive operations")?;
         writeln!(file, "# Review carefully before running!")?;
         writeln!(file, "# Generated on {}", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "# Set to exit on error")?;
         writeln!(file, "set -e")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "# Function to create directory structure")?;
         writeln!(file, "create_parent_dirs() {{")?;
         writeln!(file, "    local file=\"$1\"")?;
@@ -234,245 +1208,877 @@ impl MediaDeduplicator {
         writeln!(file, "        mkdir -p \"$target_dir/$parent_dir\"")?;
         writeln!(file, "    fi")?;
         writeln!(file, "}}")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
+        writeln!(file, "# Files may change between generating and running this script, so each")?;
+        writeln!(file, "# step first checks the size and checksum recorded for its file")?;
+        self.write_check_function(&mut file, "unchanged", "skipping $file: changed since this script was generated")?;
         writeln!(file, "# Create backup directory")?;
-        writeln!(
-            file,
-            "BACKUP_DIR=\"{}/backup_{}\"",
-            self.script_dir.display(),
-            Local::now().format("%Y%m%d_%H%M%S")
-        )?;
+        writeln!(file, "BACKUP_DIR={}", shell::quote(&self.backup_dir))?;
         writeln!(file, "mkdir -p \"$BACKUP_DIR\"")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "# Operations are grouped by directory for easier review")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
+        
+        // The undo script shares the backup directory; its steps are only
+        // known once the analysis is done and are appended in reverse
+        let mut undo = File::create(&self.undo_script_path)?;
+        writeln!(undo, "#!/usr/bin/env bash")?;
+        writeln!(undo)?;
+        writeln!(undo, "# Reverses the steps of {} that ran, newest first:", shell::comment(self.destructive_script_path.file_name().unwrap_or_default()))?;
+        writeln!(undo, "# removed files are restored from the backup directory and renames are")?;
+        writeln!(undo, "# moved back. Steps that did not run are left alone.")?;
+        writeln!(undo, "# Generated on {}", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+        writeln!(undo)?;
+        writeln!(undo, "# Set to exit on error")?;
+        writeln!(undo, "set -e")?;
+        writeln!(undo)?;
+        writeln!(undo, "# Every restored file is checked against the size and checksum recorded")?;
+        writeln!(undo, "# for it when the scripts were generated")?;
+        self.write_check_function(&mut undo, "restored", "restored $file does not match its recorded checksum")?;
+        writeln!(undo, "BACKUP_DIR={}", shell::quote(&self.backup_dir))?;
+        writeln!(undo)?;
+        
+        Ok(())
+    }
+    
+    /// Write a bash function `name SIZE CHECKSUM FILE [KEPT]` that asks this
+    /// binary whether FILE still has the given size and checksum, and the
+    /// same permissions, owner and mtime as KEPT if given, printing `warning`
+    /// and failing if it does not.
+    fn write_check_function(&self, file: &mut File, name: &str, warning: &str) -> Result<()> {
+        let executable = env::current_exe()?;
+        writeln!(file, "DEDUPLICATE_RS=\"${{DEDUPLICATE_RS:-}}\"")?;
+        writeln!(file, "if [ -z \"$DEDUPLICATE_RS\" ]; then")?;
+        writeln!(file, "    DEDUPLICATE_RS={}", shell::quote(&executable))?;
+        writeln!(file, "fi")?;
+        writeln!(file)?;
+        writeln!(file, "{}() {{", name)?;
+        writeln!(file, "    local size=\"$1\"")?;
+        writeln!(file, "    local checksum=\"$2\"")?;
+        writeln!(file, "    local file=\"$3\"")?;
+        writeln!(file, "    local same_as=()")?;
+        writeln!(file, "    if [ $# -gt 3 ]; then")?;
+        writeln!(file, "        same_as=(--same-metadata-as \"$4\")")?;
+        writeln!(file, "    fi")?;
+        writeln!(file, "    if \"$DEDUPLICATE_RS\" --hash {} --check-file \"$file\" --expect-size \"$size\" --expect-checksum \"$checksum\" \"${{same_as[@]}}\"; then", self.hash_type.key())?;
+        writeln!(file, "        return 0")?;
+        writeln!(file, "    fi")?;
+        writeln!(file, "    echo \"WARNING: {}\" >&2", warning)?;
+        writeln!(file, "    return 1")?;
+        writeln!(file, "}}")?;
+        writeln!(file)?;
+        Ok(())
+    }
+    
+    /// Append the undo steps collected by the analysis, last step first.
+    fn finish_undo_script(&self) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&self.undo_script_path)?;
+        
+        for step in self.undo_steps.iter().rev() {
+            writeln!(file, "{}", step)?;
+        }
+        
+        Ok(())
+    }
+    
+    fn load_database(&mut self) -> Result<()> {
+        if !self.checksum_db_path.exists() {
+            return Ok(());
+        }
+        
+        let backup_path = self.checksum_db_path.with_extension("txt.bak");
+        println!("Backing up old checksum database to {}", backup_path.display());
+        fs::copy(&self.checksum_db_path, &backup_path)?;
+        
+        if self.rehash {
+            // Truncate the existing file to start fresh
+            self.create_database(&self.checksum_db_path)?;
+            println!("Starting with a fresh checksum database");
+            return Ok(());
+        }
+        
+        // Databases written before the hash's current scheme (chunk-XOR
+        // XXH3-SIMD, block-chained T1HA, pre-release XXH3) hold values that no longer match
+        // anything this tool computes
+        let format = self.database_format()?;
+        if format != 0 && format < self.hash_type.digest_format() {
+            let legacy_path = self.checksum_db_path.with_extension("txt.legacy");
+            println!(
+                "WARNING: {} was written by an older {} scheme and is incompatible; moving it to {}",
+                self.checksum_db_path.display(),
+                self.hash_type,
+                legacy_path.display()
+            );
+            fs::rename(&self.checksum_db_path, &legacy_path)?;
+            self.create_database(&self.checksum_db_path)?;
+            return Ok(());
+        }
+        
+        // Each row is preceded by a comment holding the metadata it was
+        // computed from; rows without one cannot be validated and are dropped.
+        // Later rows for the same path supersede earlier ones.
+        let reader = BufReader::new(File::open(&self.checksum_db_path)?);
+        let mut rows: BTreeMap<PathBuf, CacheEntry> = BTreeMap::new();
+        let mut meta: Option<(FileStamp, Option<(HashType, String)>)> = None;
+        let mut total_rows = 0;
+        
+        for line in reader.lines() {
+            let line = line?;
+            if let Some(meta_line) = line.strip_prefix("# ") {
+                meta = CacheEntry::parse_meta_line(meta_line);
+                continue;
+            }
+            
+            let Some((checksum, path)) = parse_checksum_line(&line) else {
+                meta = None;
+                continue;
+            };
+            total_rows += 1;
+            if let Some((stamp, confirm)) = meta.take() {
+                rows.insert(path, CacheEntry { stamp, checksum, confirm });
+            }
+        }
+        
+        // Keep only rows whose file still exists with unchanged metadata
+        for (path, entry) in rows {
+            match fs::metadata(&path) {
+                Ok(metadata) if FileStamp::from_metadata(&metadata) == entry.stamp => {
+                    self.cache.insert(path, entry);
+                },
+                _ => {},
+            }
+        }
+        self.pruned_rows = total_rows - self.cache.len();
+        
+        // Rewrite the database with the surviving rows
+        let temp_path = self.checksum_db_path.with_extension("txt.tmp");
+        {
+            let mut file = self.create_database(&temp_path)?;
+            let mut entries: Vec<_> = self.cache.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (path, entry) in entries {
+                writeln!(file, "{}", entry.meta_line())?;
+                writeln!(file, "{}", format_checksum_line(&entry.checksum, path))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.checksum_db_path)?;
+        
+        println!(
+            "Loaded {} cached checksums, pruned {} stale or missing rows",
+            self.cache.len(),
+            self.pruned_rows
+        );
+        
+        Ok(())
+    }
+    
+    /// Create an empty checksum database, starting with a header comment
+    /// that records the hash type and database format.
+    fn create_database(&self, path: &Path) -> Result<File> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{} hash={} format={}", DATABASE_HEADER, self.hash_type.key(), DATABASE_FORMAT)?;
+        Ok(file)
+    }
+    
+    /// Format of the existing checksum database: 0 for an empty file, 1 for
+    /// a database without a header.
+    fn database_format(&self) -> Result<u32> {
+        let mut reader = BufReader::new(File::open(&self.checksum_db_path)?);
+        let mut first_line = String::new();
+        if reader.read_line(&mut first_line)? == 0 {
+            return Ok(0);
+        }
+        
+        let format = first_line.strip_prefix(DATABASE_HEADER)
+            .and_then(|fields| {
+                fields.split_whitespace()
+                    .find_map(|field| field.strip_prefix("format="))
+                    .and_then(|value| value.parse().ok())
+            })
+            .unwrap_or(1);
+        Ok(format)
+    }
+    
+    fn process_all_directories(&mut self) -> Result<()> {
+        // Stage 1: every indexed media file together with its byte length
+        let mut candidates: Vec<(PathBuf, u64)> = Vec::new();
+        let mut stamps: Vec<FileStamp> = Vec::new();
+        
+        // Hard links to one file are a single logical file: only the first
+        // path seen is hashed, the others are recorded as its aliases
+        let mut first_paths: HashMap<(u64, u64), usize> = HashMap::new();
+        let mut hard_links: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        
+        for dir in &self.file_index {
+            let dir_name = self.get_relative_path(&dir.path);
+            let display_name = if dir_name.as_os_str().is_empty() { PathBuf::from("root") } else { dir_name };
+            
+            println!("Found {} media files in {}", dir.files.len(), display_name.display());
+            
+            for media_file in &dir.files {
+                if let Some(file_id) = media_file.stamp.file_id() {
+                    if let Some(&first) = first_paths.get(&file_id) {
+                        hard_links.entry(candidates[first].0.clone())
+                            .or_default()
+                            .push(media_file.path.clone());
+                        continue;
+                    }
+                    first_paths.insert(file_id, candidates.len());
+                }
+                candidates.push((media_file.path.clone(), media_file.stamp.size));
+                stamps.push(media_file.stamp);
+            }
+        }
+        self.hard_links = hard_links;
+        
+        // Stage 2: a file whose size no other file shares cannot have a duplicate
+        let mut size_counts: HashMap<u64, usize> = HashMap::new();
+        for (_, size) in &candidates {
+            *size_counts.entry(*size).or_default() += 1;
+        }
+        
+        // Checksums from an earlier run are reused while the file's size,
+        // mtime, device and inode are unchanged
+        let cached: Vec<Option<CacheEntry>> = candidates.iter().zip(&stamps)
+            .map(|((path, _), stamp)| {
+                self.cache.get(path)
+                    .filter(|entry| entry.stamp == *stamp)
+                    .cloned()
+            })
+            .collect();
+        let mut uncached_sizes: HashSet<u64> = HashSet::new();
+        for ((_, size), cached) in candidates.iter().zip(&cached) {
+            if cached.is_none() {
+                uncached_sizes.insert(*size);
+            }
+        }
+        
+        // Stage 3: hash the head and tail of same-size files; large media files
+        // that merely share a size usually differ early on. Size groups whose
+        // members are all cached skip straight to their cached checksums.
+        let partial_enabled = self.partial_head_bytes > 0 || self.partial_tail_bytes > 0;
+        let mut partial_hashes: Vec<Option<(String, bool)>> = vec![None; candidates.len()];
+        let mut partial_counts: HashMap<(u64, String), usize> = HashMap::new();
+        
+        if partial_enabled {
+            let indices: Vec<usize> = (0..candidates.len())
+                .filter(|&i| {
+                    let size = candidates[i].1;
+                    size_counts[&size] >= 2 && uncached_sizes.contains(&size)
+                })
+                .collect();
+            let (hash_type, head, tail) = (self.hash_type, self.partial_head_bytes, self.partial_tail_bytes);
+            let results = self.hash_in_parallel(&candidates, &indices, |path, size| {
+                let (digest, bytes_read, whole_file) = hash_file_partial(hash_type, path, size, head, tail)?;
+                Ok(((digest, whole_file), bytes_read))
+            });
+            
+            for (i, result) in indices.into_iter().zip(results) {
+                let (partial_checksum, whole_file) = result?;
+                *partial_counts.entry((candidates[i].1, partial_checksum.clone())).or_default() += 1;
+                partial_hashes[i] = Some((partial_checksum, whole_file));
+            }
+        }
+        
+        // Stage 4: fully hash only the files that still collide. Small files were
+        // read completely by the partial stage and need no second pass.
+        let mut full_hashes: Vec<Option<String>> = vec![None; candidates.len()];
+        let mut indices = Vec::new();
+        for (i, (_, size)) in candidates.iter().enumerate() {
+            if size_counts[size] < 2 {
+                continue;
+            }
+            match &partial_hashes[i] {
+                Some((partial_checksum, whole_file)) => {
+                    if partial_counts[&(*size, partial_checksum.clone())] < 2 {
+                        continue;
+                    }
+                    if let Some(entry) = &cached[i] {
+                        full_hashes[i] = Some(entry.checksum.clone());
+                    } else if *whole_file {
+                        full_hashes[i] = Some(partial_checksum.clone());
+                    } else {
+                        indices.push(i);
+                    }
+                },
+                None => match &cached[i] {
+                    Some(entry) => full_hashes[i] = Some(entry.checksum.clone()),
+                    None => indices.push(i),
+                },
+            }
+        }
+        
+        let hash_type = self.hash_type;
+        let results = self.hash_in_parallel(&candidates, &indices, |path, _| hash_file(hash_type, path));
+        for (i, result) in indices.into_iter().zip(results) {
+            full_hashes[i] = Some(result?);
+        }
         
-        Ok(())
-    }
-    
-    fn load_database(&mut self) -> Result<()> {
-        if !self.checksum_db_path.exists() {
-            return Ok(());
+        // Stage 5 (two-tier mode): re-hash the members of groups that still
+        // collide on the fast hash with the confirming cryptographic hash
+        let mut confirm_digests: Vec<Option<String>> = vec![None; candidates.len()];
+        let mut confirm_fresh = vec![false; candidates.len()];
+        if let Some(confirm_type) = self.confirm_hash {
+            let mut group_sizes: HashMap<&str, usize> = HashMap::new();
+            for file_checksum in full_hashes.iter().flatten() {
+                *group_sizes.entry(file_checksum.as_str()).or_default() += 1;
+            }
+            
+            let mut indices = Vec::new();
+            for (i, file_checksum) in full_hashes.iter().enumerate() {
+                let Some(file_checksum) = file_checksum else { continue };
+                if group_sizes[file_checksum.as_str()] < 2 {
+                    continue;
+                }
+                match cached[i].as_ref().and_then(|entry| entry.confirm_digest(confirm_type)) {
+                    Some(digest) => confirm_digests[i] = Some(digest),
+                    None => indices.push(i),
+                }
+            }
+            
+            let results = self.hash_in_parallel(&candidates, &indices, |path, _| hash_file(confirm_type, path));
+            for (i, result) in indices.into_iter().zip(results) {
+                confirm_digests[i] = Some(result?);
+                confirm_fresh[i] = true;
+            }
         }
         
-        // Always start fresh - don't reuse old hash database to avoid stale data
-        // Just create a backup of the old database and start new
-        let backup_path = self.checksum_db_path.with_extension("txt.bak");
-        if self.checksum_db_path.exists() {
-            println!("Backing up old checksum database to {}", backup_path.display());
-            fs::copy(&self.checksum_db_path, &backup_path)?;
-            // Truncate the existing file to start fresh
-            File::create(&self.checksum_db_path)?;
+        // Results are recorded in scan order, so the duplicate groups do not
+        // depend on which worker finished first
+        let files = candidates.into_iter().zip(stamps).zip(cached).zip(full_hashes)
+            .zip(confirm_digests).zip(confirm_fresh);
+        for ((((((media_path, size), stamp), cached), file_checksum), confirm_digest), confirm_fresh) in files {
+            self.total_files += 1;
+            
+            let media_filename = media_path.file_name().unwrap_or_default();
+            
+            // Names that are not valid UTF-8 are never rewritten
+            if media_filename.to_str().is_some_and(|filename| self.has_numeric_suffix(filename)) {
+                self.rename_candidates += 1;
+            }
+            
+            let file_checksum = match file_checksum {
+                Some(file_checksum) => file_checksum,
+                None => {
+                    self.unique_files += 1;
+                    if size_counts[&size] < 2 {
+                        self.size_unique_files += 1;
+                    } else {
+                        self.partial_unique_files += 1;
+                    }
+                    continue;
+                }
+            };
+            if cached.is_some() {
+                self.cached_files += 1;
+                println!("Using cached checksum: {} ({}...)", Path::new(media_filename).display(), &file_checksum[..8]);
+            } else {
+                println!("Calculating checksum: {} ({}...)", Path::new(media_filename).display(), &file_checksum[..8]);
+            }
+            
+            // Update the database with the fresh checksum and confirming digest
+            if cached.is_none() || confirm_fresh {
+                let confirm = self.confirm_hash.zip(confirm_digest.clone())
+                    .or_else(|| cached.and_then(|entry| entry.confirm));
+                self.add_to_database(&media_path, stamp, &file_checksum, confirm)?;
+            }
+            
+            if let Some(digest) = confirm_digest {
+                self.confirm_digests.insert(media_path.clone(), digest);
+            }
+            self.record_checksum(&media_path, file_checksum);
         }
         
-        // We'll recalculate all hashes for the current files
-        println!("Starting with a fresh checksum database");
+        println!("{} files ruled out by size alone", self.size_unique_files);
+        if partial_enabled {
+            println!("{} files ruled out by partial hash", self.partial_unique_files);
+        }
         
         Ok(())
     }
     
-    fn process_all_directories(&mut self, dirs: &[PathBuf]) -> Result<()> {
-        for dir_path in dirs {
-            let dir_name = self.get_relative_path(dir_path);
-            let display_name = if dir_name.is_empty() { "root".to_string() } else { dir_name.clone() };
+    /// Hash `candidates[i]` for every `i` in `indices` on a pool of worker
+    /// threads. Workers send results back over a channel; they are returned in
+    /// the order of `indices` whatever order they complete in.
+    fn hash_in_parallel<T, F>(
+        &mut self,
+        candidates: &[(PathBuf, u64)],
+        indices: &[usize],
+        hash: F,
+    ) -> Vec<Result<T>>
+    where
+        T: Send,
+        F: Fn(&Path, u64) -> Result<(T, u64)> + Sync,
+    {
+        let stage_start = Instant::now();
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<Option<Result<T>>> = indices.iter().map(|_| None).collect();
+        let workers = self.jobs.min(indices.len()).max(1);
+        
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
             
-            println!("Examining directory: {}", display_name);
+            for _ in 0..workers {
+                let sender = sender.clone();
+                let (next, hash) = (&next, &hash);
+                scope.spawn(move || loop {
+                    let slot = next.fetch_add(1, Ordering::Relaxed);
+                    if slot >= indices.len() {
+                        break;
+                    }
+                    let (path, size) = &candidates[indices[slot]];
+                    if sender.send((slot, hash(path, *size))).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
             
-            let mut media_files = Vec::new();
+            for (slot, result) in receiver {
+                results[slot] = Some(result.map(|(value, bytes_read)| {
+                    self.total_bytes_processed += bytes_read;
+                    value
+                }));
+                self.update_peak_memory();
+            }
+        });
+        
+        // Workers overlap, so the stage is charged its wall-clock time
+        self.hashing_time += stage_start.elapsed();
+        
+        results.into_iter()
+            .map(|result| result.expect("every hash job reports a result"))
+            .collect()
+    }
+    
+    fn record_checksum(&mut self, media_path: &Path, file_checksum: String) {
+        self.checksum_to_files.entry(file_checksum).or_default()
+            .push(media_path.to_path_buf());
+    }
+    
+    /// Split duplicate groups whose members disagree on the confirming digest.
+    fn confirm_duplicate_groups(&mut self) -> Result<()> {
+        let Some(confirm_type) = self.confirm_hash else { return Ok(()) };
+        let digests = std::mem::take(&mut self.confirm_digests);
+        let reason = format!("{} digests differ", confirm_type);
+        let result = self.split_duplicate_groups(&reason, |a, b| Ok(digests.get(a) == digests.get(b)));
+        self.confirm_digests = digests;
+        result
+    }
+    
+    /// Split every duplicate group into classes of byte-identical files.
+    fn verify_duplicate_groups(&mut self) -> Result<()> {
+        self.split_duplicate_groups("contents differ", files_identical)
+    }
+    
+    /// Partition every duplicate group into classes whose members `same`
+    /// considers equal. The first class keeps the original checksum; the
+    /// others are re-keyed as `<checksum>#<n>` and reported as hash collisions.
+    fn split_duplicate_groups<F>(&mut self, reason: &str, mut same: F) -> Result<()>
+    where
+        F: FnMut(&Path, &Path) -> Result<bool>,
+    {
+        let mut checksums: Vec<String> = self.checksum_to_files.iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(checksum, _)| checksum.clone())
+            .collect();
+        checksums.sort();
+        
+        for checksum in checksums {
+            let files = self.checksum_to_files.remove(&checksum).unwrap();
+            let mut classes: Vec<Vec<PathBuf>> = Vec::new();
             
-            for entry in fs::read_dir(dir_path)? {
-                let entry = entry?;
-                let path = entry.path();
-                
-                if path.is_file() {
-                    if let Some(ext) = path.extension() {
-                        let ext_str = ext.to_string_lossy().to_lowercase();
-                        if VIDEO_FORMATS.contains(&ext_str.as_ref()) {
-                            media_files.push(path);
-                        }
+            for file_path in files {
+                let mut matched = None;
+                for (i, class) in classes.iter().enumerate() {
+                    if same(&class[0], &file_path)? {
+                        matched = Some(i);
+                        break;
                     }
                 }
+                match matched {
+                    Some(i) => classes[i].push(file_path),
+                    None => classes.push(vec![file_path]),
+                }
             }
             
-            println!("Found {} media files in {}", media_files.len(), display_name);
+            if classes.len() > 1 {
+                println!(
+                    "Hash collision ({}): {} files with checksum {}... split into {} groups",
+                    reason,
+                    classes.iter().map(Vec::len).sum::<usize>(),
+                    &checksum[..8],
+                    classes.len()
+                );
+                self.hash_collisions.push((checksum.clone(), classes.clone()));
+            }
             
-            for media_path in media_files {
-                self.total_files += 1;
-                
-                let media_filename = media_path.file_name()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .into_owned();
-                
-                // Always calculate a fresh checksum
-                let file_checksum = self.calculate_hash(&media_path)?;
-                println!("Calculating checksum: {} ({}...)", media_filename, &file_checksum[..8]);
-                
-                // Update the database with the fresh checksum
-                self.add_to_database(&media_path, &file_checksum)?;
+            for (i, class) in classes.into_iter().enumerate() {
+                let key = if i == 0 { checksum.clone() } else { format!("{}#{}", checksum, i) };
+                self.checksum_to_files.insert(key, class);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn classify_duplicates(&mut self) {
+        // The copy under the highest-priority root comes first and is kept
+        let roots = &self.roots;
+        for files in self.checksum_to_files.values_mut() {
+            files.sort_by_key(|file_path| roots.iter().position(|root| file_path.starts_with(root)));
+        }
+        
+        for (checksum, files) in &self.checksum_to_files {
+            self.unique_files += 1;
+            
+            let existing_dir = self.get_dir_path(&files[0]);
+            for file_path in files.iter().skip(1) {
+                let current_dir = self.get_dir_path(file_path);
                 
-                if !self.checksum_to_file.contains_key(&file_checksum) {
-                    self.checksum_to_file.insert(
-                        file_checksum.clone(),
-                        media_path.to_string_lossy().into_owned(),
-                    );
-                    self.checksum_to_files.entry(file_checksum).or_insert_with(Vec::new)
-                        .push(media_path.to_string_lossy().into_owned());
-                    self.unique_files += 1;
+                if existing_dir == current_dir {
+                    self.same_dir_dupes += 1;
+                    self.dir_dupes.entry(current_dir).or_default()
+                        .insert(checksum.clone());
                 } else {
-                    let media_path_str = media_path.to_string_lossy().into_owned();
-                    self.checksum_to_files.entry(file_checksum.clone()).or_insert_with(Vec::new)
-                        .push(media_path_str.clone());
-                    
-                    let existing_file = self.checksum_to_file.get(&file_checksum).unwrap();
-                    let existing_dir = self.get_dir_path(existing_file);
-                    let current_dir = self.get_dir_path(&media_path_str);
-                    
-                    if existing_dir == current_dir {
-                        self.same_dir_dupes += 1;
-                        self.dir_dupes.entry(current_dir).or_insert_with(Vec::new)
-                            .push(file_checksum.clone());
-                    } else {
-                        self.cross_dir_dupes_count += 1;
-                        self.cross_dir_dupes.insert(file_checksum);
-                    }
+                    self.cross_dir_dupes_count += 1;
+                    self.cross_dir_dupes.insert(checksum.clone());
                 }
-                
-                if self.has_numeric_suffix(&media_filename) {
-                    self.rename_candidates += 1;
+            }
+        }
+    }
+    
+    /// Share the extents of every duplicate with the copy kept for its group,
+    /// leaving out those the apply steps remove or replace.
+    fn share_duplicate_extents(&mut self, mode: ShareMode) -> Result<()> {
+        let replaced: HashSet<&Path> = self.actions.iter()
+            .filter(|action| !matches!(action.operation, Operation::Rename(_)))
+            .map(|action| action.path.as_path())
+            .collect();
+        let mut groups: Vec<&Vec<PathBuf>> = self.checksum_to_files.values()
+            .filter(|files| files.len() > 1)
+            .collect();
+        groups.sort();
+        
+        let mut shared_files = 0;
+        let mut shared_bytes = 0;
+        let mut share_differs = Vec::new();
+        let mut share_fallbacks = Vec::new();
+        
+        for files in groups {
+            let keep_file = self.keep_file_for(files);
+            for file_path in files.iter().filter(|file_path| file_path.as_path() != keep_file) {
+                if replaced.contains(file_path.as_path()) {
+                    continue;
+                }
+                // One file that cannot be read or changed must not stop the
+                // others, nor the apply steps that follow
+                match share_pair(mode, keep_file, file_path) {
+                    Ok(Shared::Bytes(bytes)) => {
+                        shared_files += 1;
+                        shared_bytes += bytes;
+                    },
+                    Ok(Shared::Differs) => {
+                        println!("Skipping {}: contents differ from {}", file_path.display(), keep_file.display());
+                        share_differs.push((file_path.clone(), keep_file.to_path_buf()));
+                    },
+                    Ok(Shared::Unsupported) => {
+                        share_fallbacks.push((file_path.clone(), keep_file.to_path_buf(), "filesystem has no extent sharing".to_string()));
+                    },
+                    Err(e) => {
+                        println!("WARNING: could not share extents of {} with {}: {}", file_path.display(), keep_file.display(), e);
+                        share_fallbacks.push((file_path.clone(), keep_file.to_path_buf(), e.to_string()));
+                    },
                 }
             }
         }
         
+        self.shared_files += shared_files;
+        self.shared_bytes += shared_bytes;
+        self.share_differs.extend(share_differs);
+        self.share_fallbacks.extend(share_fallbacks);
         Ok(())
     }
     
-    fn analyze_within_directory_duplicates(&self) -> Result<()> {
+    fn analyze_within_directory_duplicates(&mut self) -> Result<()> {
         let mut file = OpenOptions::new()
             .append(true)
             .open(&self.destructive_script_path)?;
+        let mut undo_steps = Vec::new();
+        let mut actions = Vec::new();
+        let mut removals = Vec::new();
         
         writeln!(file, "###")?;
         writeln!(file, "# Within-Directory Duplicates")?;
         writeln!(file, "###")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         
         for (dir, checksums) in &self.dir_dupes {
-            writeln!(file, "# Processing directory: {}", dir)?;
-            writeln!(file, "mkdir -p \"$BACKUP_DIR/{}/\"", dir)?;
-            writeln!(file, "")?;
+            writeln!(file, "# Processing directory: {}", shell::comment(dir))?;
+            writeln!(file, "mkdir -p {}", shell::backup_path(dir))?;
+            writeln!(file)?;
             
             for checksum in checksums {
                 let all_files = self.checksum_to_files.get(checksum).unwrap();
                 
-                let dir_files: Vec<&String> = all_files.iter()
+                let dir_files: Vec<&PathBuf> = all_files.iter()
                     .filter(|&file| self.get_dir_path(file) == *dir)
                     .collect();
                 
                 if dir_files.len() > 1 {
-                    let mut keep_file = "";
-                    let mut longest_len = 0;
-                    
-                    for &file in &dir_files {
-                        let filename = Path::new(file).file_name()
-                            .unwrap_or_default()
-                            .to_string_lossy();
-                        
-                        if !self.has_numeric_suffix(&filename) {
-                            keep_file = file;
-                            break;
-                        }
-                        
-                        let file_len = filename.len();
-                        if file_len > longest_len {
-                            longest_len = file_len;
-                            keep_file = file;
-                        }
-                    }
-                    
-                    if keep_file.is_empty() && !dir_files.is_empty() {
-                        keep_file = dir_files[0];
-                    }
+                    let keep_file = self.choose_keep_file(&dir_files);
                     
                     writeln!(file, "# Duplicate set with checksum: {}...", &checksum[..8])?;
-                    writeln!(file, "# Keeping: {}", Path::new(keep_file).file_name().unwrap_or_default().to_string_lossy())?;
+                    writeln!(file, "# Keeping: {}", shell::comment(keep_file.file_name().unwrap_or_default()))?;
                     
                     for &file_path in &dir_files {
                         if file_path != keep_file {
-                            let filename = Path::new(file_path).file_name()
-                                .unwrap_or_default()
-                                .to_string_lossy();
+                            let filename = file_path.file_name().unwrap_or_default();
+                            
+                            if let Some(aliases) = self.hard_links.get(file_path) {
+                                writeln!(
+                                    file,
+                                    "# Skipping {}: also hard-linked as {}, removing it frees no space",
+                                    shell::comment(filename),
+                                    self.comment_paths(aliases)
+                                )?;
+                                continue;
+                            }
                             
-                            writeln!(file, "# Backup and remove: {}", filename)?;
-                            writeln!(file, "cp \"{}\" \"$BACKUP_DIR/{}/{}\"", file_path, dir, filename)?;
-                            writeln!(file, "rm \"{}\"", file_path)?;
+                            let backup = shell::backup_path(dir.join(filename));
+                            writeln!(file, "# Backup and remove: {}", shell::comment(filename))?;
+                            writeln!(file, "{}", self.unchanged_guard(file_path)?)?;
+                            writeln!(file, "    cp {} {}", shell::quote(file_path), backup)?;
+                            writeln!(file, "    rm {}", shell::quote(file_path))?;
+                            writeln!(file, "fi")?;
+                            undo_steps.push(self.undo_remove_step(file_path, &backup)?);
+                            actions.push(self.planned_action(file_path, &dir.join(filename), Operation::Remove)?);
+                            removals.push(file_path.clone());
                         }
                     }
                     
-                    writeln!(file, "")?;
+                    writeln!(file)?;
                 }
             }
         }
         
+        self.undo_steps.extend(undo_steps);
+        self.actions.extend(actions);
+        self.within_dir_removals.extend(removals);
         Ok(())
     }
     
-    fn analyze_cross_directory_duplicates(&self) -> Result<()> {
+    fn analyze_cross_directory_duplicates(&mut self) -> Result<()> {
         let mut file = OpenOptions::new()
             .append(true)
             .open(&self.destructive_script_path)?;
+        let mut undo_steps = Vec::new();
+        let mut actions = Vec::new();
+        let mut link_fallbacks = Vec::new();
+        let mut symlink_targets = HashSet::new();
         
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "###")?;
         writeln!(file, "# Cross-Directory Duplicates")?;
         writeln!(file, "###")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "# WARNING: These are duplicates across different directories.")?;
-        writeln!(file, "# The script does not automatically remove them as they may serve different purposes.")?;
-        writeln!(file, "# Review and uncomment the sections below if you want to remove them.")?;
-        writeln!(file, "")?;
+        match self.link_mode {
+            LinkMode::None => {
+                writeln!(file, "# The script does not automatically remove them as they may serve different purposes.")?;
+                writeln!(file, "# Review and uncomment the sections below if you want to remove them.")?;
+            },
+            LinkMode::Hardlink => {
+                writeln!(file, "# Each copy on the same filesystem as the kept copy, and with the same")?;
+                writeln!(file, "# permissions, owner and mtime, is replaced with a hard link to it, so every")?;
+                writeln!(file, "# path keeps existing.")?;
+            },
+            LinkMode::Symlink => {
+                writeln!(file, "# Each copy is replaced with a symbolic link to the kept copy, so every path")?;
+                writeln!(file, "# keeps existing. Moving or renaming the kept copy leaves its links dangling.")?;
+            },
+        }
+        writeln!(file)?;
         
         for checksum in &self.cross_dir_dupes {
             let all_files = self.checksum_to_files.get(checksum).unwrap();
             
+            let keep_file = self.keep_file_for(all_files);
+            
             writeln!(file, "# Duplicate set with checksum: {}...", &checksum[..8])?;
-            writeln!(file, "# First encountered: {} in {}", 
-                Path::new(&all_files[0]).file_name().unwrap_or_default().to_string_lossy(),
-                self.get_dir_path(&all_files[0]))?;
+            writeln!(file, "# Keeping (highest-priority copy): {} in {}", 
+                shell::comment(keep_file.file_name().unwrap_or_default()),
+                shell::comment(self.get_dir_path(keep_file)))?;
             writeln!(file, "# Other copies:")?;
             
-            for i in 1..all_files.len() {
-                let file_path = &all_files[i];
+            for file_path in all_files.iter().filter(|file_path| file_path.as_path() != keep_file) {
                 let file_dir = self.get_dir_path(file_path);
-                let filename = Path::new(file_path).file_name()
-                    .unwrap_or_default()
-                    .to_string_lossy();
+                let filename = file_path.file_name().unwrap_or_default();
+                
+                writeln!(file, "# {} in {}", shell::comment(filename), shell::comment(&file_dir))?;
+                if self.within_dir_removals.contains(file_path) {
+                    writeln!(file, "# (removed above as a within-directory duplicate)")?;
+                    writeln!(file, "#")?;
+                    continue;
+                }
+                if let Some(aliases) = self.hard_links.get(file_path) {
+                    writeln!(file, "# (also hard-linked as {}; removing it frees no space)", self.comment_paths(aliases))?;
+                }
+                let backup = shell::backup_path(file_dir.join(filename));
+                
+                if self.link_mode != LinkMode::None {
+                    if self.hard_links.contains_key(file_path) {
+                        writeln!(file, "# Not linked: the other names would keep the copy's data")?;
+                    } else if self.link_mode == LinkMode::Symlink {
+                        let target = self.symlink_target(file_path, keep_file)?;
+                        self.write_symlink_step(&mut file, file_path, keep_file, &target, &file_dir, &backup)?;
+                        undo_steps.push(self.undo_symlink_step(file_path, keep_file, &backup)?);
+                        actions.push(self.planned_action(file_path, &file_dir.join(filename), Operation::Symlink(target))?);
+                        symlink_targets.insert(keep_file.to_path_buf());
+                    } else if self.cache.get(file_path).map(|entry| entry.stamp.dev) != self.cache.get(keep_file).map(|entry| entry.stamp.dev) {
+                        writeln!(file, "# Not linked: on a different filesystem from the kept copy")?;
+                        link_fallbacks.push((file_path.clone(), keep_file.to_path_buf(), "different filesystem"));
+                    } else if !same_metadata(file_path, keep_file) {
+                        writeln!(file, "# Not linked: its permissions, owner or mtime differ from the kept copy's")?;
+                        link_fallbacks.push((file_path.clone(), keep_file.to_path_buf(), "permissions, owner or mtime differ"));
+                    } else {
+                        self.write_hard_link_step(&mut file, file_path, keep_file, &file_dir, &backup)?;
+                        undo_steps.push(self.undo_hard_link_step(file_path, keep_file, &backup)?);
+                        actions.push(self.planned_action(file_path, &file_dir.join(filename), Operation::HardLink(keep_file.to_path_buf()))?);
+                    }
+                    writeln!(file, "#")?;
+                    continue;
+                }
                 
-                writeln!(file, "# {} in {}", filename, file_dir)?;
-                writeln!(file, "# cp \"{}\" \"$BACKUP_DIR/{}/{}\"", file_path, file_dir, filename)?;
-                writeln!(file, "# rm \"{}\"", file_path)?;
+                writeln!(file, "# {}", self.unchanged_guard(file_path)?)?;
+                writeln!(file, "#     mkdir -p {}", shell::backup_path(&file_dir))?;
+                writeln!(file, "#     cp {} {}", shell::quote(file_path), backup)?;
+                writeln!(file, "#     rm {}", shell::quote(file_path))?;
+                writeln!(file, "# fi")?;
                 writeln!(file, "#")?;
+                
+                // Commented out like the removal it reverses
+                let step = self.undo_remove_step(file_path, &backup)?;
+                let step: Vec<String> = step.lines()
+                    .map(|line| if line.starts_with('#') { line.to_string() } else { format!("# {}", line) })
+                    .collect();
+                undo_steps.push(format!("{}\n#\n", step.join("\n")));
             }
             
-            writeln!(file, "")?;
+            writeln!(file)?;
+        }
+        
+        // Steps are written in reverse, so the heading goes last
+        if !undo_steps.is_empty() && self.link_mode == LinkMode::None {
+            undo_steps.push("# Cross-directory duplicates: uncomment the steps matching those\n# uncommented in the removal script\n".to_string());
+        }
+        self.undo_steps.extend(undo_steps);
+        self.actions.extend(actions);
+        self.link_fallbacks.extend(link_fallbacks);
+        self.symlink_targets.extend(symlink_targets);
+        Ok(())
+    }
+    
+    /// Write the script block replacing `file_path` with a hard link to
+    /// `keep_file`. The link is made under a temporary name and renamed over
+    /// the copy, so the path never goes missing.
+    fn write_hard_link_step(
+        &self,
+        file: &mut File,
+        file_path: &Path,
+        keep_file: &Path,
+        backup_dir: &Path,
+        backup: &str,
+    ) -> Result<()> {
+        let temp_path = shell::quote(temp_sibling(file_path));
+        // The link takes on the kept file's metadata, so the copy's must
+        // still match it when the script runs
+        writeln!(
+            file,
+            "if unchanged {} {} && unchanged {}; then",
+            self.check_args(file_path)?,
+            shell::quote(keep_file),
+            self.check_args(keep_file)?
+        )?;
+        writeln!(file, "    mkdir -p {}", shell::backup_path(backup_dir))?;
+        writeln!(file, "    cp -p {} {}", shell::quote(file_path), backup)?;
+        writeln!(file, "    ln -f {} {}", shell::quote(keep_file), temp_path)?;
+        writeln!(file, "    mv -f {} {}", temp_path, shell::quote(file_path))?;
+        writeln!(file, "fi")?;
+        Ok(())
+    }
+    
+    /// Write the script block replacing `file_path` with a symbolic link to
+    /// `keep_file` whose contents are `target`, made under a temporary name
+    /// and renamed over the copy like a hard link.
+    fn write_symlink_step(
+        &self,
+        file: &mut File,
+        file_path: &Path,
+        keep_file: &Path,
+        target: &Path,
+        backup_dir: &Path,
+        backup: &str,
+    ) -> Result<()> {
+        let temp_path = shell::quote(temp_sibling(file_path));
+        writeln!(file, "if unchanged {} && unchanged {}; then", self.check_args(file_path)?, self.check_args(keep_file)?)?;
+        writeln!(file, "    mkdir -p {}", shell::backup_path(backup_dir))?;
+        writeln!(file, "    cp -p {} {}", shell::quote(file_path), backup)?;
+        writeln!(file, "    ln -sfn {} {}", shell::quote(target), temp_path)?;
+        writeln!(file, "    mv -f {} {}", temp_path, shell::quote(file_path))?;
+        writeln!(file, "fi")?;
+        Ok(())
+    }
+    
+    /// The contents of a symbolic link at `file_path` leading to `keep_file`,
+    /// in the configured style. Relative paths are worked out between the
+    /// real directories, as the link is resolved from where it really lives.
+    fn symlink_target(&self, file_path: &Path, keep_file: &Path) -> Result<PathBuf> {
+        if self.symlink_style == SymlinkStyle::Absolute {
+            return Ok(keep_file.to_path_buf());
         }
+        let real_dir = |path: &Path| -> Result<PathBuf> {
+            let dir = path.parent().ok_or_else(|| anyhow!("No parent directory: {}", path.display()))?;
+            Ok(fs::canonicalize(dir)?)
+        };
+        let keep_path = real_dir(keep_file)?.join(keep_file.file_name().unwrap_or_default());
+        Ok(relative_path(&real_dir(file_path)?, &keep_path))
+    }
+    
+    /// Append a final script step reporting symbolic links left dangling by
+    /// changes made outside the script, e.g. a kept copy moved or removed
+    /// between generating and running it.
+    fn write_dangling_link_check(&self) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&self.destructive_script_path)?;
         
+        let roots: Vec<String> = self.roots.iter().map(shell::quote).collect();
+        writeln!(file)?;
+        writeln!(file, "###")?;
+        writeln!(file, "# Dangling Symlink Check")?;
+        writeln!(file, "###")?;
+        writeln!(file)?;
+        writeln!(file, "\"$DEDUPLICATE_RS\" --check-links --filepath {} || echo \"WARNING: some symlinks are dangling; run undo.sh to restore the copies\" >&2", roots.join(" "))?;
         Ok(())
     }
     
-    fn analyze_rename_candidates(&mut self, dirs: &[PathBuf]) -> Result<()> {
+    fn analyze_rename_candidates(&mut self) -> Result<()> {
         let mut file = OpenOptions::new()
             .append(true)
             .open(&self.destructive_script_path)?;
         
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "###")?;
         writeln!(file, "# Filename Cleanup (Remove Numeric Suffixes)")?;
         writeln!(file, "###")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         writeln!(file, "# Files with numeric suffixes can be renamed to cleaner versions")?;
         writeln!(file, "# Be careful with these operations to avoid name conflicts")?;
-        writeln!(file, "")?;
+        writeln!(file)?;
         
         // Create a set of files that are duplicates within the same directory
         let mut duplicate_files = HashSet::new();
@@ -480,58 +2086,53 @@ impl MediaDeduplicator {
             for checksum in checksums {
                 let all_files = self.checksum_to_files.get(checksum).unwrap();
                 
-                let dir_files: Vec<&String> = all_files.iter()
+                let dir_files: Vec<&PathBuf> = all_files.iter()
                     .filter(|&file| self.get_dir_path(file) == *dir)
                     .collect();
                 
                 if dir_files.len() > 1 {
                     // These are duplicates within the same directory
                     for &file_path in &dir_files {
-                        duplicate_files.insert(file_path.to_string());
+                        duplicate_files.insert(file_path.as_path());
                     }
                 }
             }
         }
         
-        for dir_path in dirs {
-            let dir_name = self.get_relative_path(dir_path);
-            let display_name = if dir_name.is_empty() { "root".to_string() } else { dir_name.clone() };
-            
-            let mut rename_files = Vec::new();
-            
-            for entry in fs::read_dir(dir_path)? {
-                let entry = entry?;
-                let path = entry.path();
-                
-                if path.is_file() {
-                    if let Some(ext) = path.extension() {
-                        let ext_str = ext.to_string_lossy().to_lowercase();
-                        if VIDEO_FORMATS.contains(&ext_str.as_ref()) {
-                            let filename = path.file_name()
-                                .unwrap_or_default()
-                                .to_string_lossy()
-                                .into_owned();
-                            
-                            // Only consider renaming files that are duplicates
-                            let path_str = path.to_string_lossy().into_owned();
-                            if self.has_numeric_suffix(&filename) && duplicate_files.contains(&path_str) {
-                                rename_files.push(path);
-                            }
-                        }
-                    }
-                }
-            }
+        // Only consider renaming files that are duplicates and are kept, and
+        // not those that symlinks point at
+        duplicate_files.retain(|file_path| {
+            !self.within_dir_removals.contains(*file_path) && !self.symlink_targets.contains(*file_path)
+        });
+        let rename_dirs: Vec<(PathBuf, Vec<PathBuf>)> = self.file_index.iter()
+            .map(|dir| {
+                let rename_files = dir.files.iter()
+                    .filter(|media_file| {
+                        // Names that are not valid UTF-8 are never rewritten
+                        let filename = media_file.path.file_name().and_then(OsStr::to_str);
+                        filename.is_some_and(|filename| self.has_numeric_suffix(filename))
+                            && duplicate_files.contains(media_file.path.as_path())
+                    })
+                    .map(|media_file| media_file.path.clone())
+                    .collect();
+                (dir.path.clone(), rename_files)
+            })
+            .collect();
+        
+        for (dir_path, rename_files) in rename_dirs {
+            let dir_name = self.get_relative_path(&dir_path);
+            let display_name = if dir_name.as_os_str().is_empty() { PathBuf::from("root") } else { dir_name };
             
             if !rename_files.is_empty() {
-                writeln!(file, "# Directory: {}", display_name)?;
-                writeln!(file, "mkdir -p \"$BACKUP_DIR/{}\"", display_name)?;
-                writeln!(file, "")?;
+                writeln!(file, "# Directory: {}", shell::comment(&display_name))?;
+                writeln!(file, "mkdir -p {}", shell::backup_path(&display_name))?;
+                writeln!(file)?;
                 
                 for file_path in rename_files {
                     let filename = file_path.file_name()
+                        .and_then(OsStr::to_str)
                         .unwrap_or_default()
-                        .to_string_lossy()
-                        .into_owned();
+                        .to_string();
                     let clean_name = self.remove_numeric_suffix(&filename);
                     
                     let mut conflict = false;
@@ -557,16 +2158,24 @@ impl MediaDeduplicator {
                         
                         let hashed_name = self.create_hashed_filename(&clean_name, &checksum);
                         
-                        writeln!(file, "# Rename with hash due to conflict: {} -> {}", filename, hashed_name)?;
-                        writeln!(file, "cp \"{}\" \"$BACKUP_DIR/{}/{}\"", file_path.display(), display_name, filename)?;
-                        writeln!(file, "mv \"{}\" \"{}/{}\"", file_path.display(), dir_path.display(), hashed_name)?;
+                        let hashed_path = dir_path.join(&hashed_name);
+                        writeln!(file, "# Rename with hash due to conflict: {} -> {}", shell::comment(&filename), shell::comment(&hashed_name))?;
+                        writeln!(file, "{}", self.unchanged_guard(&file_path)?)?;
+                        writeln!(file, "    cp {} {}", shell::quote(&file_path), shell::backup_path(display_name.join(&filename)))?;
+                        writeln!(file, "    mv {} {}", shell::quote(&file_path), shell::quote(&hashed_path))?;
+                        self.undo_steps.push(self.undo_rename_step(&file_path, &hashed_path)?);
+                        self.actions.push(self.planned_action(&file_path, &display_name.join(&filename), Operation::Rename(hashed_path))?);
                     } else {
-                        writeln!(file, "# Rename to remove suffix: {} -> {}", filename, clean_name)?;
-                        writeln!(file, "cp \"{}\" \"$BACKUP_DIR/{}/{}\"", file_path.display(), display_name, filename)?;
-                        writeln!(file, "mv \"{}\" \"{}/{}\"", file_path.display(), dir_path.display(), clean_name)?;
+                        writeln!(file, "# Rename to remove suffix: {} -> {}", shell::comment(&filename), shell::comment(&clean_name))?;
+                        writeln!(file, "{}", self.unchanged_guard(&file_path)?)?;
+                        writeln!(file, "    cp {} {}", shell::quote(&file_path), shell::backup_path(display_name.join(&filename)))?;
+                        writeln!(file, "    mv {} {}", shell::quote(&file_path), shell::quote(&clean_path))?;
+                        self.undo_steps.push(self.undo_rename_step(&file_path, &clean_path)?);
+                        self.actions.push(self.planned_action(&file_path, &display_name.join(&filename), Operation::Rename(clean_path))?);
                     }
+                    writeln!(file, "fi")?;
                     
-                    writeln!(file, "")?;
+                    writeln!(file)?;
                 }
             }
         }
@@ -575,14 +2184,96 @@ impl MediaDeduplicator {
     }
     
     fn display_results(&self) {
-        println!("");
+        println!();
         println!("=== Deduplication Analysis Complete ===");
         println!("Total files processed: {}", self.total_files);
         println!("Unique files found: {}", self.unique_files);
+        println!("  Ruled out by size alone: {}", self.size_unique_files);
+        println!("  Ruled out by partial hash: {}", self.partial_unique_files);
         println!("Within-directory duplicates: {}", self.same_dir_dupes);
         println!("Cross-directory duplicates: {}", self.cross_dir_dupes_count);
         println!("Filename cleanup candidates: {}", self.rename_candidates);
-        println!("");
+        println!("Checksums reused from cache: {}", self.cached_files);
+        println!("Stale database rows pruned: {}", self.pruned_rows);
+        if !self.exclusion_counts.is_empty() {
+            println!("Paths excluded by filter rules: {}", self.exclusion_counts.values().sum::<usize>());
+            for (rule, count) in &self.exclusion_counts {
+                println!("  {}: {}", rule, count);
+            }
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            println!("Files outside the size limits: {}", self.size_filtered);
+        }
+        if !self.hard_links.is_empty() {
+            println!(
+                "Hard-linked paths (same file, not duplicates): {}",
+                self.hard_links.values().map(Vec::len).sum::<usize>()
+            );
+            for (file_path, aliases) in &self.hard_links {
+                println!("  {}", file_path.display());
+                for alias in aliases {
+                    println!("    = {}", alias.display());
+                }
+            }
+        }
+        if !self.link_fallbacks.is_empty() {
+            println!("Not linked to the kept copy: {}", self.link_fallbacks.len());
+            for (file_path, keep_file, reason) in &self.link_fallbacks {
+                println!("  {} (kept: {}): {}", file_path.display(), keep_file.display(), reason);
+            }
+        }
+        if let Some(mode) = self.share_extents {
+            println!("Duplicates sharing extents with the kept copy ({}): {}", mode, self.shared_files);
+            println!("  Bytes reclaimed: {}", ByteSize(self.shared_bytes));
+            if !self.share_differs.is_empty() {
+                println!("  Not shared (contents differ): {}", self.share_differs.len());
+                for (file_path, keep_file) in &self.share_differs {
+                    println!("    {} (kept: {})", file_path.display(), keep_file.display());
+                }
+            }
+            if !self.share_fallbacks.is_empty() {
+                println!("  Not shared: {}", self.share_fallbacks.len());
+                for (file_path, keep_file, reason) in &self.share_fallbacks {
+                    println!("    {} (kept: {}): {}", file_path.display(), keep_file.display(), reason);
+                }
+            }
+        }
+        if !self.empty_files.is_empty() {
+            println!("Zero-length files (not treated as duplicates): {}", self.empty_files.len());
+            for file_path in &self.empty_files {
+                println!("  {}", file_path.display());
+            }
+        }
+        if !self.symlink_loops.is_empty() {
+            println!("Symlink loops skipped: {}", self.symlink_loops.len());
+            for (link, ancestor) in &self.symlink_loops {
+                println!("  {} -> {}", link.display(), ancestor.display());
+            }
+        }
+        if !self.skipped_symlinks.is_empty() {
+            println!("Followed symlinks skipped (target already scanned): {}", self.skipped_symlinks.len());
+            for (link, target) in &self.skipped_symlinks {
+                println!("  {} -> {}", link.display(), target.display());
+            }
+        }
+        if self.sniff {
+            println!("Extension does not match detected container: {}", self.extension_mismatches.len());
+            for (file_path, container) in &self.extension_mismatches {
+                println!("  {} (detected {})", file_path.display(), container);
+            }
+        }
+        if self.verify || self.confirm_hash.is_some() {
+            println!("Hash collisions split off: {}", self.hash_collisions.len());
+            for (checksum, classes) in &self.hash_collisions {
+                println!("  Checksum {}...:", &checksum[..8]);
+                for (i, class) in classes.iter().enumerate() {
+                    for file_path in class {
+                        println!("    [{}] {}", i, file_path.display());
+                    }
+                }
+            }
+        }
+        println!();
         
         // Display performance metrics
         let total_time = self.start_time.elapsed();
@@ -600,47 +2291,55 @@ impl MediaDeduplicator {
         println!("Data processed: {}", bytes_processed);
         println!("Throughput: {}/s", throughput);
         println!("Peak memory usage: {}", memory_usage);
-        println!("");
+        println!();
         
         println!("All checksums have been saved to: {}", self.checksum_db_path.display());
-        println!("");
+        println!();
         println!("IMPORTANT: Potentially destructive operations have been written to:");
         println!("{}", self.destructive_script_path.display());
-        println!("");
+        println!();
         println!("Please review this script carefully before running it!");
         println!("It will:");
-        println!("1. Backup files before removing duplicates");
+        println!("1. Backup files before removing duplicates, skipping any file that changed since this run");
         println!("2. Remove within-directory duplicates (keeping one copy)");
-        println!("3. List cross-directory duplicates (commented out, must be manually enabled)");
+        match self.link_mode {
+            LinkMode::None => println!("3. List cross-directory duplicates (commented out, must be manually enabled)"),
+            LinkMode::Hardlink => println!("3. Replace cross-directory duplicates with hard links to the kept copy"),
+            LinkMode::Symlink => println!("3. Replace cross-directory duplicates with symbolic links to the kept copy"),
+        }
         println!("4. Clean up filenames by removing numeric suffixes");
-        println!("");
+        if self.link_mode == LinkMode::Symlink {
+            println!("5. Report any symbolic links left dangling");
+        }
+        println!();
+        println!("To reverse the steps that ran, restoring from the backup directory:");
+        println!("bash {}", self.undo_script_path.display());
+        println!();
         println!("To apply these changes, run: bash {}", self.destructive_script_path.display());
     }
     
     fn get_checksum_from_database(&self, file_path: &Path) -> Result<String> {
-        let file = File::open(&self.checksum_db_path)?;
-        let reader = BufReader::new(file);
-        let path_str = file_path.to_string_lossy();
-        
-        for line in reader.lines() {
-            let line = line?;
-            if line.contains(&format!("  {}", path_str)) {
-                let parts: Vec<&str> = line.splitn(2, "  ").collect();
-                if parts.len() == 2 {
-                    return Ok(parts[0].to_string());
-                }
-            }
-        }
-        
-        Err(anyhow!("Checksum not found for file: {}", path_str))
+        self.cache.get(file_path)
+            .map(|entry| entry.checksum.clone())
+            .ok_or_else(|| anyhow!("Checksum not found for file: {}", file_path.display()))
     }
     
-    fn add_to_database(&self, file_path: &Path, checksum: &str) -> Result<()> {
+    fn add_to_database(
+        &mut self,
+        file_path: &Path,
+        stamp: FileStamp,
+        checksum: &str,
+        confirm: Option<(HashType, String)>,
+    ) -> Result<()> {
         let mut file = OpenOptions::new()
             .append(true)
             .open(&self.checksum_db_path)?;
         
-        writeln!(file, "{}  {}", checksum, file_path.to_string_lossy())?;
+        let entry = CacheEntry { stamp, checksum: checksum.to_string(), confirm };
+        writeln!(file, "{}", entry.meta_line())?;
+        writeln!(file, "{}", format_checksum_line(checksum, file_path))?;
+        
+        self.cache.insert(file_path.to_path_buf(), entry);
         
         Ok(())
     }
@@ -649,85 +2348,223 @@ impl MediaDeduplicator {
         // Track hash calculation time
         let hash_start = Instant::now();
         
-        let mut file = File::open(file_path)?;
-        let mut buffer = Vec::new();
-        let bytes_read = file.read_to_end(&mut buffer)?;
+        let (hash_string, bytes_read) = hash_file(self.hash_type, file_path)?;
+        
+        self.update_hash_metrics(hash_start, bytes_read);
         
+        Ok(hash_string)
+    }
+    
+    fn update_hash_metrics(&mut self, hash_start: Instant, bytes_read: u64) {
         // Add to total bytes processed
-        self.total_bytes_processed += bytes_read as u64;
-        
-        // Calculate hash based on the selected algorithm
-        let hash_string = match self.hash_type {
-            HashType::XXH3 => {
-                // Use XXH3 hash64 which is extremely fast
-                let hash_value = hash64(&buffer);
-                format!("{:016x}", hash_value)
-            },
-            HashType::XXH3_128 => {
-                // Use XXH3 128-bit hash for better collision resistance
-                let hash_value = hash128(&buffer);
-                format!("{:032x}", hash_value)
-            },
-            HashType::Xxh3Simd => {
-                // Use XXH3 with a chunked approach to better utilize SIMD instructions
-                // Process the buffer in chunks of optimal size for SIMD operations
-                const CHUNK_SIZE: usize = 16 * 1024; // 16KB chunks for better SIMD utilization
-                let mut combined_hash = 0u64;
-                
-                // Process in chunks to better utilize cache and SIMD instructions
-                for chunk in buffer.chunks(CHUNK_SIZE) {
-                    let chunk_hash = hash64(chunk);
-                    // XOR combine the hash values
-                    combined_hash ^= chunk_hash.rotate_left((combined_hash % 64) as u32);
-                }
-                
-                format!("{:016x}", combined_hash)
-            },
-            HashType::Blake3 => {
-                // Use Blake3 which is optimized for modern CPUs with SIMD
-                let hash_value = blake3::hash(&buffer);
-                hash_value.to_hex().to_string()
-            },
-            HashType::T1HA => {
-                // Use T1HA (Fast Positive Hash)
-                let hash_value = t1ha0(&buffer, 0);
-                format!("{:016x}", hash_value)
-            }
-        };
+        self.total_bytes_processed += bytes_read;
         
         // Track hashing time
         let elapsed = hash_start.elapsed();
         self.hashing_time += elapsed;
         
-        // Update memory usage
-        self.system_info.refresh_all();
-        let pid = std::process::id() as usize;
-        if let Some(process) = self.system_info.process(sysinfo::Pid::from(pid)) {
+        self.update_peak_memory();
+    }
+    
+    fn update_peak_memory(&mut self) {
+        let pid = sysinfo::Pid::from(std::process::id() as usize);
+        self.system_info.refresh_process(pid);
+        if let Some(process) = self.system_info.process(pid) {
             let memory = process.memory();
             if memory > self.peak_memory_usage {
                 self.peak_memory_usage = memory;
             }
         }
-        
-        Ok(hash_string)
     }
     
-    fn get_relative_path(&self, path: &Path) -> String {
-        path.strip_prefix(&self.root_path)
-            .map(|p| p.to_string_lossy().into_owned())
-            .unwrap_or_else(|_| "".to_string())
+    fn has_media_extension(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|ext| {
+            self.extensions.contains(&ext.to_string_lossy().to_lowercase())
+        })
+    }
+    
+    /// Whether a sniffed container belongs to one of the scanned media types.
+    fn container_selected(&self, container: Container) -> bool {
+        container.extensions().iter().any(|ext| self.extensions.contains(*ext))
+    }
+    
+    /// Index of the root `path` lies under; lower indices have higher priority.
+    fn root_index(&self, path: &Path) -> Option<usize> {
+        self.roots.iter().position(|root| path.starts_with(root))
+    }
+    
+    fn root_label(&self, index: usize) -> String {
+        format!("root{}", index + 1)
+    }
+    
+    /// Path relative to its root. With several roots the relative path is
+    /// prefixed with the root's label, so equal subdirectories of different
+    /// roots stay apart.
+    fn relative_to_root(&self, path: &Path) -> Option<PathBuf> {
+        let index = self.root_index(path)?;
+        let relative = path.strip_prefix(&self.roots[index]).ok()?;
+        if self.roots.len() == 1 {
+            return Some(relative.to_path_buf());
+        }
+        Some(Path::new(&self.root_label(index)).join(relative))
+    }
+    
+    fn get_relative_path(&self, path: &Path) -> PathBuf {
+        self.relative_to_root(path).unwrap_or_default()
+    }
+    
+    fn get_dir_path(&self, filepath: &Path) -> PathBuf {
+        let rel_path = self.relative_to_root(filepath)
+            .unwrap_or_else(|| filepath.to_path_buf());
+            
+        rel_path.parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+    
+    /// Arguments for the scripts' check functions: the size and checksum this
+    /// run recorded for `file_path`, followed by the quoted path.
+    fn check_args(&self, file_path: &Path) -> Result<String> {
+        let entry = self.cache.get(file_path)
+            .ok_or_else(|| anyhow!("Checksum not found for file: {}", file_path.display()))?;
+        Ok(format!("{} {} {}", entry.stamp.size, entry.checksum, shell::quote(file_path)))
+    }
+    
+    /// A step for --apply, backing `file_path` up to `backup` below the backup
+    /// directory before the operation.
+    fn planned_action(&self, file_path: &Path, backup: &Path, operation: Operation) -> Result<Action> {
+        let entry = self.cache.get(file_path)
+            .ok_or_else(|| anyhow!("Checksum not found for file: {}", file_path.display()))?;
+        Ok(Action {
+            path: file_path.to_path_buf(),
+            backup: self.backup_dir.join(backup),
+            size: entry.stamp.size,
+            checksum: entry.checksum.clone(),
+            operation,
+        })
+    }
+    
+    /// The script line opening a block that only runs while `file_path` still
+    /// has the size and checksum this run recorded for it.
+    fn unchanged_guard(&self, file_path: &Path) -> Result<String> {
+        Ok(format!("if unchanged {}; then", self.check_args(file_path)?))
     }
     
-    fn get_dir_path(&self, filepath: &str) -> String {
-        let path = Path::new(filepath);
-        let rel_path = path.strip_prefix(&self.root_path)
-            .map(|p| p.to_string_lossy().into_owned())
-            .unwrap_or_else(|_| filepath.to_string());
+    /// Undo script block restoring `file_path` from its copy at `backup`, if
+    /// the removal ran.
+    fn undo_remove_step(&self, file_path: &Path, backup: &str) -> Result<String> {
+        let quoted = shell::quote(file_path);
+        Ok(format!(
+            "# Restore: {}\n\
+             if [ ! -e {} ] && [ -e {} ]; then\n    \
+                 cp {} {}\n    \
+                 restored {} || true\n\
+             fi\n",
+            shell::comment(file_path),
+            quoted, backup,
+            backup, quoted,
+            self.check_args(file_path)?
+        ))
+    }
+    
+    /// Undo script block putting the copy at `backup` back in place of the
+    /// hard link to `keep_file`, if the link was made.
+    fn undo_hard_link_step(&self, file_path: &Path, keep_file: &Path, backup: &str) -> Result<String> {
+        let (quoted, temp_path) = (shell::quote(file_path), shell::quote(temp_sibling(file_path)));
+        Ok(format!(
+            "# Restore in place of hard link: {}\n\
+             if [ {} -ef {} ] && [ -e {} ]; then\n    \
+                 cp -p {} {}\n    \
+                 mv -f {} {}\n    \
+                 restored {} || true\n\
+             fi\n",
+            shell::comment(file_path),
+            quoted, shell::quote(keep_file), backup,
+            backup, temp_path,
+            temp_path, quoted,
+            self.check_args(file_path)?
+        ))
+    }
+    
+    /// Undo script block restoring `file_path` from `backup` in place of the
+    /// symbolic link to `keep_file` that replaced it.
+    fn undo_symlink_step(&self, file_path: &Path, keep_file: &Path, backup: &str) -> Result<String> {
+        let (quoted, temp_path) = (shell::quote(file_path), shell::quote(temp_sibling(file_path)));
+        Ok(format!(
+            "# Restore in place of symlink: {}\n\
+             if [ -L {} ] && [ {} -ef {} ] && [ -e {} ]; then\n    \
+                 cp -p {} {}\n    \
+                 mv -f {} {}\n    \
+                 restored {} || true\n\
+             fi\n",
+            shell::comment(file_path),
+            quoted, quoted, shell::quote(keep_file), backup,
+            backup, temp_path,
+            temp_path, quoted,
+            self.check_args(file_path)?
+        ))
+    }
+    
+    /// Undo script block moving `renamed` back to `file_path`, if the rename
+    /// ran.
+    fn undo_rename_step(&self, file_path: &Path, renamed: &Path) -> Result<String> {
+        let (quoted, renamed_quoted) = (shell::quote(file_path), shell::quote(renamed));
+        Ok(format!(
+            "# Undo rename: {} -> {}\n\
+             if [ ! -e {} ] && [ -e {} ]; then\n    \
+                 mv {} {}\n    \
+                 restored {} || true\n\
+             fi\n",
+            shell::comment(renamed), shell::comment(file_path),
+            quoted, renamed_quoted,
+            renamed_quoted, quoted,
+            self.check_args(file_path)?
+        ))
+    }
+    
+    /// A list of paths for a script comment.
+    fn comment_paths(&self, paths: &[PathBuf]) -> String {
+        paths.iter().map(shell::comment).collect::<Vec<_>>().join(", ")
+    }
+    
+    /// The copy kept from a duplicate group, chosen among those under the
+    /// highest-priority root holding one.
+    fn keep_file_for<'a>(&self, files: &'a [PathBuf]) -> &'a Path {
+        let best_root = self.root_index(&files[0]);
+        let best_files: Vec<&PathBuf> = files.iter()
+            .filter(|file_path| self.root_index(file_path) == best_root)
+            .collect();
+        self.choose_keep_file(&best_files)
+    }
+    
+    /// Pick the copy to keep from a set of identical files: the first without
+    /// a numeric suffix, otherwise the one with the longest name.
+    fn choose_keep_file<'a>(&self, files: &[&'a PathBuf]) -> &'a Path {
+        // Removing one name of a hard-linked file frees no space
+        if let Some(&file) = files.iter().find(|&&file| self.hard_links.contains_key(file)) {
+            return file;
+        }
+        
+        let mut keep_file: Option<&Path> = None;
+        let mut longest_len = 0;
+        
+        for &file in files {
+            let filename = file.file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            
+            if !self.has_numeric_suffix(&filename) {
+                return file;
+            }
             
-        Path::new(&rel_path)
-            .parent()
-            .map(|p| p.to_string_lossy().into_owned())
-            .unwrap_or_else(|| "".to_string())
+            let file_len = filename.len();
+            if file_len > longest_len {
+                longest_len = file_len;
+                keep_file = Some(file);
+            }
+        }
+        
+        keep_file.unwrap_or(files[0])
     }
     
     fn has_numeric_suffix(&self, filename: &str) -> bool {
@@ -774,7 +2611,162 @@ impl MediaDeduplicator {
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    if let (Some(file_path), Some(size), Some(checksum)) = (&args.check_file, args.expect_size, &args.expect_checksum) {
+        let unchanged = file_unchanged(args.hash, file_path, size, checksum)?
+            && args.same_metadata_as.as_deref().is_none_or(|keep_file| metadata_matches(file_path, keep_file));
+        std::process::exit(if unchanged { 0 } else { 1 });
+    }
+    if args.check_links {
+        let roots = if args.filepath.is_empty() { vec![env::current_dir()?] } else { args.filepath.clone() };
+        let dangling = report_dangling_symlinks(&roots);
+        std::process::exit(if dangling == 0 { 0 } else { 1 });
+    }
+    if let Some(journal_path) = &args.resume {
+        println!("{}", apply::resume(journal_path)?);
+        return Ok(());
+    }
+    if let Some(journal_path) = &args.rollback {
+        let summary = apply::rollback(journal_path)?;
+        println!("{}", summary);
+        std::process::exit(if summary.irreversible == 0 { 0 } else { 1 });
+    }
     let mut deduplicator = MediaDeduplicator::new(args.hash)?;
     deduplicator.run(args)?;
     Ok(())
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::os::unix::ffi::OsStrExt;
+    use std::process::Command;
+
+    #[test]
+    fn database_rows_round_trip() {
+        let names: &[&[u8]] = &[
+            b"/media/plain name.mp4",
+            b"/media/back\\slash.mp4",
+            b"/media/new\nline\r.mp4",
+            b"/media/caf\xe9 \xff.mp4",
+            b"/media/\\x41.mp4",
+            b"/media/\\\n\xfe\\xzz.mp4",
+        ];
+        for name in names {
+            let path = Path::new(OsStr::from_bytes(name));
+            let line = format_checksum_line("0123abcd", path);
+            assert!(!line.contains(['\n', '\r']), "{:?}", line);
+            assert_eq!(parse_checksum_line(&line), Some(("0123abcd".to_string(), path.to_path_buf())), "{:?}", line);
+        }
+
+        // Rows as coreutils writes them
+        assert_eq!(format_checksum_line("0123abcd", Path::new("/media/plain name.mp4")), "0123abcd  /media/plain name.mp4");
+        assert_eq!(format_checksum_line("0123abcd", Path::new("/media/\\x41.mp4")), "\\0123abcd  /media/\\\\x41.mp4");
+        assert_eq!(format_checksum_line("0123abcd", Path::new("/media/a\nb.mp4")), "\\0123abcd  /media/a\\nb.mp4");
+        assert_eq!(parse_checksum_line("\\0123abcd  /media/bad\\q.mp4"), None);
+    }
+
+    /// Digests of the empty input and of a file spanning several hash
+    /// buffers, from the reference implementations' one-shot functions.
+    const KNOWN_ANSWERS: &[(HashType, &str, &str)] = &[
+        (HashType::XXH3, "2d06800538d394c2", "f9cb1eabc9244f37"),
+        (HashType::XXH3_128, "99aa06d3014798d86001c324468d497f", "a7c4208a7fc793c8f9cb1eabc9244f37"),
+        (HashType::Xxh3Simd, "2d06800538d394c2", "f9cb1eabc9244f37"),
+        (
+            HashType::Blake3,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            "3944d1f68ff5cb3539ee48991dcdfefec6bf9afa13d0b71396d3fd8eaa0365bf",
+        ),
+        (HashType::T1HA, "3c8426e33cb41606", "b10dfee6b4c22a08"),
+        (
+            HashType::Sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "2e5e321c61c38df2ec144156f2f70495fa0551a75f1d8cd234ffe54b01d11fba",
+        ),
+    ];
+
+    #[test]
+    fn hash_file_matches_known_answers() {
+        let dir = TestDir::new("main-known-answers");
+        let (empty, long) = (dir.join("empty"), dir.join("long"));
+        fs::write(&empty, b"").unwrap();
+        let data: Vec<u8> = (0..2 * HASH_BUFFER_SIZE + 12345).map(|i| (i % 251) as u8).collect();
+        fs::write(&long, &data).unwrap();
+
+        for &(hash_type, empty_digest, long_digest) in KNOWN_ANSWERS {
+            assert_eq!(hash_file(hash_type, &empty).unwrap(), (empty_digest.to_string(), 0), "{}", hash_type);
+            assert_eq!(hash_file(hash_type, &long).unwrap(), (long_digest.to_string(), data.len() as u64), "{}", hash_type);
+        }
+    }
+
+    #[test]
+    fn relative_paths_between_directories() {
+        let cases = [
+            ("/media/a", "/media/a/clip.mp4", "clip.mp4"),
+            ("/media/b", "/media/a/clip.mp4", "../a/clip.mp4"),
+            ("/media/a/b/c", "/media/a/clip.mp4", "../../clip.mp4"),
+            ("/media", "/media/a/b/clip.mp4", "a/b/clip.mp4"),
+            ("/mnt/x/y", "/media/a/clip.mp4", "../../../media/a/clip.mp4"),
+        ];
+        for (from, to, expected) in cases {
+            assert_eq!(relative_path(Path::new(from), Path::new(to)), Path::new(expected), "{} -> {}", from, to);
+        }
+    }
+
+    /// Run the analysis over `media` with `args`, writing the scripts,
+    /// database and backups to `out`, and return the planned steps.
+    fn planned_actions(media: &Path, out: &Path, args: &[&str]) -> Vec<Action> {
+        let mut deduplicator = MediaDeduplicator::new(HashType::XXH3).unwrap();
+        deduplicator.checksum_db_path = out.join("xxh3sum.txt");
+        deduplicator.destructive_script_path = out.join("potentially-destructive-remove.sh");
+        deduplicator.undo_script_path = out.join("undo.sh");
+        deduplicator.backup_dir = out.join("backup");
+        let media = media.to_str().unwrap();
+        let args = Args::parse_from(["deduplicate-rs", "--filepath", media].iter().chain(args));
+        deduplicator.run(args).unwrap();
+        deduplicator.actions
+    }
+
+    #[test]
+    fn symlink_targets_keep_their_names() {
+        let dir = TestDir::new("main-symlink-targets");
+        let media = dir.join("media");
+        for (subdir, name) in [("a", "clip_1.mp4"), ("a", "clip_2.mp4"), ("b", "clip_3.mp4")] {
+            fs::create_dir_all(media.join(subdir)).unwrap();
+            fs::write(media.join(subdir).join(name), b"duplicate contents").unwrap();
+        }
+        let media = fs::canonicalize(&media).unwrap();
+        let keep_file = media.join("a").join("clip_1.mp4");
+        let is_rename = |action: &Action| action.path == keep_file && matches!(action.operation, Operation::Rename(_));
+
+        // Without links, the kept copy loses its suffix
+        let out = TestDir::new("main-symlink-targets-out");
+        assert!(planned_actions(&media, &out, &[]).iter().any(is_rename));
+
+        let out = TestDir::new("main-symlink-targets-linked");
+        let actions = planned_actions(&media, &out, &["--link-mode", "symlink"]);
+        assert!(actions.iter().any(|action| {
+            action.path == media.join("b").join("clip_3.mp4") && action.operation == Operation::Symlink(PathBuf::from("../a/clip_1.mp4"))
+        }));
+        assert!(!actions.iter().any(is_rename));
+    }
+
+    #[test]
+    fn sha256sum_checks_database_rows() {
+        let dir = TestDir::new("main-sha256sum");
+        let lines: Vec<String> = ["plain name.mp4", "back\\slash.mp4", "new\nline.mp4", "\\x41.mp4"].iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, name).unwrap();
+                let (checksum, _) = hash_file(HashType::Sha256, &path).unwrap();
+                format_checksum_line(&checksum, &path)
+            })
+            .collect();
+        let database = dir.join("sha256sum.txt");
+        fs::write(&database, lines.join("\n") + "\n").unwrap();
+
+        // Not every system has coreutils' sha256sum
+        let Ok(output) = Command::new("sha256sum").arg("-c").arg(&database).output() else { return };
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    }
+}
@@ -0,0 +1,78 @@
+//! Byte-exact access to paths, which on Unix are arbitrary bytes and need
+//! not be valid UTF-8.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+
+/// The bytes of `s`. Off Unix, names are Unicode and this is their UTF-8
+/// encoding.
+#[cfg(unix)]
+pub fn as_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn as_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    match s.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// The inverse of [`as_bytes`].
+#[cfg(unix)]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// Render `s` as text, passing each character through `escape_char` and
+/// writing every byte that is not valid UTF-8 as `\xNN`.
+pub fn escape<F>(s: &OsStr, mut escape_char: F) -> String
+where
+    F: FnMut(char, &mut String),
+{
+    let bytes = as_bytes(s);
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            escape_char(c, &mut escaped);
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// Reverse the escapes written for paths in line-based files: `\\`, `\n`,
+/// `\r`, `\t` and `\xNN`. Returns `None` for any other escape.
+pub fn unescape(text: &str) -> Option<OsString> {
+    let mut unescaped = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            unescaped.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push(b'\\'),
+            'n' => unescaped.push(b'\n'),
+            'r' => unescaped.push(b'\r'),
+            't' => unescaped.push(b'\t'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                unescaped.push(u8::from_str_radix(&hex, 16).ok()?);
+            },
+            _ => return None,
+        }
+    }
+    Some(from_bytes(unescaped))
+}
@@ -0,0 +1,377 @@
+//! Extent sharing between identical files, on filesystems that support it
+//! (Btrfs, XFS, ...). Both files keep their own inode and metadata but point
+//! at the same blocks on disk, through the Linux `FIDEDUPERANGE` and
+//! `FICLONE` ioctls. `FIEMAP` tells how much of a file was already shared, so
+//! only newly shared blocks are counted.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// How the blocks of a duplicate are shared with the kept copy, selected
+/// with `--share-extents`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareMode {
+    Dedupe,  // FIDEDUPERANGE: the kernel compares the data before sharing
+    Clone,   // FICLONE: reflink the whole file, trusting our own verification
+}
+
+impl std::fmt::Display for ShareMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareMode::Dedupe => write!(f, "FIDEDUPERANGE"),
+            ShareMode::Clone => write!(f, "FICLONE"),
+        }
+    }
+}
+
+impl std::str::FromStr for ShareMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dedupe" | "fideduperange" => Ok(ShareMode::Dedupe),
+            "clone" | "reflink" | "ficlone" => Ok(ShareMode::Clone),
+            _ => Err(format!("Unknown extent sharing mode: {}", s)),
+        }
+    }
+}
+
+/// Result of sharing one duplicate's extents with the kept copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shared {
+    // The duplicate shares its extents with the kept copy; this many bytes of
+    // it were not shared with any file before
+    Bytes(u64),
+    // The kernel found the contents differ and shared nothing
+    Differs,
+    // The filesystem, or the pair of filesystems, cannot share extents
+    Unsupported,
+}
+
+/// Make `dest` share its extents with `source`, which must have the same
+/// contents. `dest`'s mtime is left as it was.
+pub fn share_extents(mode: ShareMode, source: &Path, dest: &Path) -> io::Result<Shared> {
+    let source_file = File::open(source)?;
+    let dest_file = open_dest(dest)?;
+    let len = source_file.metadata()?.len();
+    if dest_file.metadata()?.len() != len {
+        return Ok(Shared::Differs);
+    }
+
+    let mtime = dest_file.metadata()?.modified()?;
+    let shared_before = match sys::shared_len(&dest_file, len) {
+        Ok(bytes) => bytes,
+        Err(e) if unsupported(&e) => return Ok(Shared::Unsupported),
+        Err(e) => return Err(e),
+    };
+    let shared = match mode {
+        ShareMode::Dedupe => sys::dedupe(&source_file, &dest_file, len).map(|_| ()),
+        ShareMode::Clone => sys::clone(&source_file, &dest_file),
+    };
+    match shared {
+        Ok(()) => {
+            // Cloning counts as a write; the contents did not change
+            dest_file.set_modified(mtime)?;
+            let shared_after = sys::shared_len(&dest_file, len)?;
+            Ok(Shared::Bytes(shared_after.saturating_sub(shared_before)))
+        },
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(Shared::Differs),
+        Err(e) if unsupported(&e) => Ok(Shared::Unsupported),
+        Err(e) => Err(e),
+    }
+}
+
+/// Open the file whose extents are replaced. Deduplication works on a
+/// read-only descriptor for the file's owner, so a file without write
+/// permission is still handled.
+fn open_dest(dest: &Path) -> io::Result<File> {
+    match File::options().read(true).write(true).open(dest) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(dest),
+        result => result,
+    }
+}
+
+/// Errors meaning the filesystem cannot share extents, rather than that
+/// something went wrong.
+fn unsupported(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::ENOSYS)
+        )
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        e.kind() == io::ErrorKind::Unsupported
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// `_IOW(0x94, 9, int)` from `linux/fs.h`.
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    /// `_IOWR(0x94, 54, struct file_dedupe_range)` from `linux/fs.h`.
+    const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+
+    /// `_IOWR('f', 11, struct fiemap)` from `linux/fs.h`.
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+    /// Extents requested per FIEMAP call.
+    const FIEMAP_BATCH: usize = 64;
+
+    /// Bytes submitted per FIDEDUPERANGE call; Btrfs handles at most 16 MiB
+    /// at a time.
+    const DEDUPE_CHUNK: u64 = 16 * 1024 * 1024;
+
+    /// `struct file_dedupe_range` followed by its single
+    /// `struct file_dedupe_range_info`.
+    #[repr(C)]
+    struct DedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    /// `struct fiemap_extent`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    /// `struct fiemap` with room for a batch of extents.
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; FIEMAP_BATCH],
+    }
+
+    /// How many of the first `len` bytes of `file` lie in extents shared
+    /// with another file or snapshot.
+    pub fn shared_len(file: &File, len: u64) -> io::Result<u64> {
+        let mut start = 0;
+        let mut shared = 0;
+        while start < len {
+            let mut map = Fiemap {
+                fm_start: start,
+                fm_length: len - start,
+                fm_flags: FIEMAP_FLAG_SYNC,
+                fm_mapped_extents: 0,
+                fm_extent_count: FIEMAP_BATCH as u32,
+                fm_reserved: 0,
+                fm_extents: [FiemapExtent::default(); FIEMAP_BATCH],
+            };
+            // SAFETY: `map` is a valid struct fiemap with room for the
+            // `fm_extent_count` extents it asks for, and outlives the call
+            if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map as *mut Fiemap) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let extents = &map.fm_extents[..map.fm_mapped_extents as usize];
+            let Some(last) = extents.last() else { break };
+            for extent in extents.iter().filter(|extent| extent.fe_flags & FIEMAP_EXTENT_SHARED != 0) {
+                // Extents are whole blocks and may start before `start` or
+                // run past the end of the file
+                let from = extent.fe_logical.max(start);
+                let to = (extent.fe_logical + extent.fe_length).min(len);
+                shared += to.saturating_sub(from);
+            }
+            if last.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+                break;
+            }
+            start = last.fe_logical + last.fe_length;
+        }
+        Ok(shared)
+    }
+
+    /// Deduplicate `len` bytes of `dest` against `source`, returning how many
+    /// the kernel shared. Contents that differ are reported as
+    /// `ErrorKind::InvalidData`.
+    pub fn dedupe(source: &File, dest: &File, len: u64) -> io::Result<u64> {
+        let mut offset = 0;
+        let mut deduped = 0;
+        while offset < len {
+            let mut range = DedupeRange {
+                src_offset: offset,
+                src_length: DEDUPE_CHUNK.min(len - offset),
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                dest_fd: dest.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            };
+            // SAFETY: `range` is a valid file_dedupe_range with room for the
+            // one destination it names, and outlives the call
+            if unsafe { libc::ioctl(source.as_raw_fd(), FIDEDUPERANGE as _, &mut range as *mut DedupeRange) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            match range.status {
+                FILE_DEDUPE_RANGE_DIFFERS => return Err(io::ErrorKind::InvalidData.into()),
+                status if status < 0 => return Err(io::Error::from_raw_os_error(-status)),
+                _ => {},
+            }
+            if range.bytes_deduped == 0 {
+                break;
+            }
+            deduped += range.bytes_deduped;
+            offset += range.bytes_deduped;
+        }
+        Ok(deduped)
+    }
+
+    /// Replace all of `dest`'s extents with `source`'s.
+    pub fn clone(source: &File, dest: &File) -> io::Result<()> {
+        // SAFETY: FICLONE takes the source descriptor by value
+        if unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub fn shared_len(_file: &File, _len: u64) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn dedupe(_source: &File, _dest: &File, _len: u64) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn clone(_source: &File, _dest: &File) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Directory on a filesystem with extent sharing, e.g. a loopback Btrfs
+    /// image:
+    ///
+    /// ```console
+    /// truncate -s 512M btrfs.img && mkfs.btrfs btrfs.img
+    /// sudo mount -o loop btrfs.img /mnt/btrfs && sudo chown $USER /mnt/btrfs
+    /// DEDUPLICATE_RS_REFLINK_DIR=/mnt/btrfs cargo test reflink
+    /// ```
+    const REFLINK_DIR_VAR: &str = "DEDUPLICATE_RS_REFLINK_DIR";
+
+    /// Deterministic contents spanning several dedupe chunks and ending in a
+    /// partial block.
+    fn contents(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn reflink_dir() -> Option<PathBuf> {
+        let dir = env::var_os(REFLINK_DIR_VAR).map(PathBuf::from);
+        if dir.is_none() {
+            eprintln!("{} is not set; skipping", REFLINK_DIR_VAR);
+        }
+        dir
+    }
+
+    #[test]
+    fn shares_identical_files() {
+        let Some(parent) = reflink_dir() else { return };
+        let dir = TestDir::below(&parent, "reflink-share");
+        let data = contents(40 * 1024 * 1024 + 123, 7);
+
+        for mode in [ShareMode::Dedupe, ShareMode::Clone] {
+            let (source, dest) = (dir.join("source"), dir.join("dest"));
+            fs::write(&source, &data).unwrap();
+            fs::write(&dest, &data).unwrap();
+            let mtime = fs::metadata(&dest).unwrap().modified().unwrap();
+
+            let shared = share_extents(mode, &source, &dest).unwrap();
+            assert_eq!(shared, Shared::Bytes(data.len() as u64), "{}", mode);
+            assert_eq!(fs::read(&dest).unwrap(), data);
+            assert_eq!(fs::metadata(&dest).unwrap().modified().unwrap(), mtime);
+
+            // Blocks shared by the first call are not counted again
+            assert_eq!(share_extents(mode, &source, &dest).unwrap(), Shared::Bytes(0), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn dedupe_refuses_differing_files() {
+        let Some(parent) = reflink_dir() else { return };
+        let dir = TestDir::below(&parent, "reflink-differs");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        let mut data = contents(1024 * 1024, 3);
+        fs::write(&source, &data).unwrap();
+        data[700_000] ^= 1;
+        fs::write(&dest, &data).unwrap();
+
+        assert_eq!(share_extents(ShareMode::Dedupe, &source, &dest).unwrap(), Shared::Differs);
+        assert_eq!(fs::read(&dest).unwrap(), data);
+    }
+
+    #[test]
+    fn fresh_files_share_no_blocks() {
+        let dir = TestDir::new("reflink-fiemap");
+        let path = dir.join("fresh");
+        let data = contents(3 * 1024 * 1024 + 17, 9);
+        fs::write(&path, &data).unwrap();
+
+        let shared = sys::shared_len(&File::open(&path).unwrap(), data.len() as u64);
+        assert!(matches!(shared, Ok(0)), "{:?}", shared);
+    }
+
+    #[test]
+    fn unsupported_filesystems_fall_back() {
+        // The temporary directory may itself support sharing; either way
+        // the call must not fail or touch the contents
+        let dir = TestDir::new("reflink-fallback");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        let data = contents(64 * 1024, 5);
+        fs::write(&source, &data).unwrap();
+        fs::write(&dest, &data).unwrap();
+
+        for mode in [ShareMode::Dedupe, ShareMode::Clone] {
+            let shared = share_extents(mode, &source, &dest).unwrap();
+            assert!(matches!(shared, Shared::Bytes(_) | Shared::Unsupported), "{}: {:?}", mode, shared);
+            assert_eq!(fs::read(&dest).unwrap(), data);
+        }
+    }
+}
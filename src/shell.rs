@@ -0,0 +1,223 @@
+//! Quoting for the generated bash script. Every path written into the script
+//! goes through [`quote`], and every name echoed in a comment through
+//! [`comment`], so no filename can change what the script does.
+
+use crate::os_bytes;
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Quote `arg` as a single bash word that expands to exactly `arg`, byte for
+/// byte.
+///
+/// Valid UTF-8 without control characters is single-quoted, where the only
+/// special case is `'` itself. Anything else uses ANSI-C `$'...'` quoting, in
+/// which bytes that are not valid UTF-8 are written as `\xNN`; this also keeps
+/// the word on one line, so commented-out commands stay inert.
+pub fn quote<S: AsRef<OsStr>>(arg: S) -> String {
+    let arg = arg.as_ref();
+    if let Some(text) = arg.to_str().filter(|text| !text.chars().any(is_control)) {
+        return format!("'{}'", text.replace('\'', r"'\''"));
+    }
+
+    let escaped = os_bytes::escape(arg, |c, quoted| match c {
+        '\\' => quoted.push_str(r"\\"),
+        '\'' => quoted.push_str(r"\'"),
+        '\n' => quoted.push_str(r"\n"),
+        '\r' => quoted.push_str(r"\r"),
+        '\t' => quoted.push_str(r"\t"),
+        c if is_control(c) => quoted.push_str(&format!(r"\x{:02x}", c as u32)),
+        c => quoted.push(c),
+    });
+    format!("$'{}'", escaped)
+}
+
+/// A path below the script's `$BACKUP_DIR`, as a single bash word.
+pub fn backup_path<P: AsRef<Path>>(relative: P) -> String {
+    format!("\"$BACKUP_DIR\"/{}", quote(relative.as_ref()))
+}
+
+/// Make `text` safe to follow `#` on a single script line.
+pub fn comment<S: AsRef<OsStr>>(text: S) -> String {
+    os_bytes::escape(text.as_ref(), |c, escaped| {
+        if is_control(c) {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    })
+}
+
+/// Characters that bash cannot be trusted to keep on one script line.
+fn is_control(c: char) -> bool {
+    c.is_ascii_control()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::io::Write;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::process::{Command, Stdio};
+
+    const HOSTILE_NAMES: &[&[u8]] = &[
+        b"",
+        b" ",
+        b"plain.mp4",
+        b"with space.mp4",
+        b"it's.mp4",
+        b"''''",
+        b"\"quoted\".mp4",
+        b"$HOME.mp4",
+        b"${IFS}x.mp4",
+        b"$(touch pwned).mp4",
+        b"`touch pwned`.mp4",
+        b"a\\b\\.mp4",
+        b"trailing\\",
+        b"new\nline.mp4",
+        b"\nrm -rf ~\n",
+        b"carriage\rreturn.mp4",
+        b"tab\there.mp4",
+        b"bell\x07.mp4",
+        b"esc\x1b[31mred.mp4",
+        b"del\x7f.mp4",
+        b"-rf",
+        b"--",
+        b"*.mp4",
+        b"?[a-z]{1,2}.mp4",
+        b"~/movie.mp4",
+        b"a;b|c&d>e<f.mp4",
+        b"!!.mp4",
+        b"#not a comment.mp4",
+        b"$'\\x41'.mp4",
+        "emoji 🎬 ümlaut.mp4".as_bytes(),
+        "\u{85}next line\u{2028}separator.mp4".as_bytes(),
+        b"latin-1 caf\xe9.mp4",
+        b"\xff\xfe\x80.mp4",
+        b"truncated \xc3",
+        b"\xc3\x28 invalid pair.mp4",
+        b"\\x41 \xe9 it's\n.mp4",
+    ];
+
+    /// Fragments mixed into the generated names: shell metacharacters,
+    /// quotes, escapes, control characters, multi-byte text and bytes that
+    /// are not valid UTF-8.
+    const ALPHABET: &[&[u8]] = &[
+        b"a", b"Z", b"0", b".", b"-", b"/", b" ", b"'", b"\"", b"`", b"$", b"\\",
+        b"!", b"#", b"*", b"?", b"[", b"]", b"{", b"}", b"(", b")", b";", b"&",
+        b"|", b"<", b">", b"~", b"=", b"%", b"x", b"\n", b"\r", b"\t", b"\x01",
+        b"\x1b", b"\x7f", "é".as_bytes(), "🎬".as_bytes(), "\u{85}".as_bytes(),
+        b"\x80", b"\xc3", b"\xe9", b"\xff",
+    ];
+
+    /// Deterministic xorshift generator, so failures are reproducible.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn generated_names(count: usize) -> Vec<OsString> {
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        (0..count)
+            .map(|_| {
+                let len = (rng.next() % 24) as usize;
+                let bytes = (0..len)
+                    .flat_map(|_| ALPHABET[(rng.next() % ALPHABET.len() as u64) as usize].iter().copied())
+                    .collect();
+                OsString::from_vec(bytes)
+            })
+            .collect()
+    }
+
+    fn all_names() -> Vec<OsString> {
+        HOSTILE_NAMES.iter()
+            .map(|name| OsString::from_vec(name.to_vec()))
+            .chain(generated_names(2000))
+            .collect()
+    }
+
+    /// Run `script` with bash, fed on standard input like a script file,
+    /// and return its standard output.
+    fn bash(script: &str) -> Vec<u8> {
+        let mut child = Command::new("bash")
+            .env("BACKUP_DIR", "/backup dir")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("bash must be installed to run these tests");
+        child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "bash failed: {}", String::from_utf8_lossy(&output.stderr));
+        output.stdout
+    }
+
+    /// Have bash print each word NUL-terminated, then split them back out.
+    fn round_trip(words: &[String]) -> Vec<OsString> {
+        let script = format!("printf '%s\\0' {}", words.join(" "));
+        let stdout = bash(&script);
+        let mut fields: Vec<OsString> = stdout.split(|&b| b == 0)
+            .map(|field| OsString::from_vec(field.to_vec()))
+            .collect();
+        // The output ends with a NUL, leaving an empty last field
+        assert_eq!(fields.pop(), Some(OsString::new()));
+        fields
+    }
+
+    #[test]
+    fn quoted_names_round_trip_through_bash() {
+        let names = all_names();
+        let quoted: Vec<String> = names.iter().map(quote).collect();
+        assert_eq!(round_trip(&quoted), names);
+    }
+
+    #[test]
+    fn backup_paths_expand_under_backup_dir() {
+        let names = all_names();
+        let quoted: Vec<String> = names.iter().map(|name| backup_path(Path::new(name))).collect();
+        let expected: Vec<OsString> = names.iter()
+            .map(|name| OsString::from_vec([b"/backup dir/", name.as_bytes()].concat()))
+            .collect();
+        assert_eq!(round_trip(&quoted), expected);
+    }
+
+    #[test]
+    fn quoted_names_stay_on_one_line() {
+        for name in all_names() {
+            let quoted = quote(&name);
+            assert!(!quoted.contains(['\n', '\r']), "{:?} quoted as {:?}", name, quoted);
+        }
+    }
+
+    #[test]
+    fn commented_commands_are_inert() {
+        let mut script = String::new();
+        for name in all_names() {
+            script.push_str(&format!(
+                "# {}\n# rm {}\n# cp {} {}\n",
+                comment(&name),
+                quote(&name),
+                quote(&name),
+                backup_path(Path::new(&name)),
+            ));
+        }
+        script.push_str("printf done");
+        assert_eq!(bash(&script), b"done");
+    }
+
+    #[test]
+    fn plain_names_are_single_quoted() {
+        assert_eq!(quote("movie.mp4"), "'movie.mp4'");
+        assert_eq!(quote("it's.mp4"), r"'it'\''s.mp4'");
+        assert_eq!(quote("new\nline"), r"$'new\nline'");
+        assert_eq!(quote(OsStr::from_bytes(b"caf\xe9")), r"$'caf\xe9'");
+        assert_eq!(comment("new\nline"), r"new\nline");
+        assert_eq!(comment(OsStr::from_bytes(b"caf\xe9")), r"caf\xe9");
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Number of bytes read from the start of a file to identify its container.
+/// Large enough for three 192-byte M2TS packets and a Matroska EBML header.
+const SNIFF_LEN: usize = 1024;
+
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+const ASF_GUID: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11,
+    0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Media container identified from a file's leading bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,        // ISO base media file (ftyp box)
+    QuickTime,  // ISO base media file with the `qt  ` brand
+    Heif,       // ISO base media file with a HEIF/AVIF image brand
+    Matroska,   // EBML header
+    WebM,       // EBML header with the `webm` doctype
+    Avi,        // RIFF....AVI
+    Wave,       // RIFF....WAVE
+    MpegTs,     // 188-byte packets starting with 0x47
+    M2ts,       // 192-byte packets (4-byte timestamp + TS packet)
+    MpegPs,     // MPEG program stream pack header
+    Flv,        // FLV signature
+    Asf,        // ASF header object GUID (wmv, wma)
+}
+
+impl Container {
+    /// Extensions a file holding this container is expected to carry.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Container::Mp4 => &["mp4", "m4v", "m4a", "mov", "3gp", "3g2"],
+            Container::QuickTime => &["mov", "qt", "mp4", "m4v"],
+            Container::Heif => &["heic", "heif", "avif"],
+            Container::Matroska => &["mkv", "mka", "mk3d"],
+            Container::WebM => &["webm", "mkv"],
+            Container::Avi => &["avi"],
+            Container::Wave => &["wav"],
+            Container::MpegTs => &["ts", "mts", "m2ts"],
+            Container::M2ts => &["m2ts", "mts", "ts"],
+            Container::MpegPs => &["mpg", "mpeg", "vob"],
+            Container::Flv => &["flv"],
+            Container::Asf => &["wmv", "wma", "asf"],
+        }
+    }
+}
+
+impl std::fmt::Display for Container {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Container::Mp4 => write!(f, "MP4"),
+            Container::QuickTime => write!(f, "QuickTime"),
+            Container::Heif => write!(f, "HEIF"),
+            Container::Matroska => write!(f, "Matroska"),
+            Container::WebM => write!(f, "WebM"),
+            Container::Avi => write!(f, "AVI"),
+            Container::Wave => write!(f, "WAVE"),
+            Container::MpegTs => write!(f, "MPEG-TS"),
+            Container::M2ts => write!(f, "M2TS"),
+            Container::MpegPs => write!(f, "MPEG-PS"),
+            Container::Flv => write!(f, "FLV"),
+            Container::Asf => write!(f, "ASF"),
+        }
+    }
+}
+
+/// Read the start of `path` and identify its container, if it has one we know.
+pub fn sniff_container(path: &Path) -> io::Result<Option<Container>> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?.take(SNIFF_LEN as u64).read_to_end(&mut header)?;
+    Ok(identify(&header))
+}
+
+fn identify(header: &[u8]) -> Option<Container> {
+    if header.len() >= 12 && &header[4..8] == b"ftyp" {
+        return Some(match &header[8..12] {
+            b"qt  " => Container::QuickTime,
+            b"heic" | b"heix" | b"hevc" | b"mif1" | b"msf1" | b"avif" => Container::Heif,
+            _ => Container::Mp4,
+        });
+    }
+
+    if header.starts_with(&EBML_MAGIC) {
+        let is_webm = header.windows(4).any(|window| window == b"webm");
+        return Some(if is_webm { Container::WebM } else { Container::Matroska });
+    }
+
+    if header.len() >= 12 && header.starts_with(b"RIFF") {
+        match &header[8..12] {
+            b"AVI " => return Some(Container::Avi),
+            b"WAVE" => return Some(Container::Wave),
+            _ => {},
+        }
+    }
+
+    if header.starts_with(b"FLV\x01") {
+        return Some(Container::Flv);
+    }
+
+    if header.starts_with(&ASF_GUID) {
+        return Some(Container::Asf);
+    }
+
+    if header.starts_with(&[0x00, 0x00, 0x01, 0xBA]) {
+        return Some(Container::MpegPs);
+    }
+
+    // A single 0x47 is common in arbitrary data; require three packets in a row
+    if has_sync_bytes(header, 0, 188) {
+        return Some(Container::MpegTs);
+    }
+    if has_sync_bytes(header, 4, 192) {
+        return Some(Container::M2ts);
+    }
+
+    None
+}
+
+fn has_sync_bytes(header: &[u8], offset: usize, packet_len: usize) -> bool {
+    (0..3).all(|i| header.get(offset + i * packet_len) == Some(&TS_SYNC_BYTE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ISO base media file starting with an `ftyp` box of `brand`.
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        [&[0x00, 0x00, 0x00, 0x18][..], b"ftyp", brand, b"\x00\x00\x02\x00isomiso2"].concat()
+    }
+
+    /// An EBML header declaring `doctype`.
+    fn ebml(doctype: &[u8]) -> Vec<u8> {
+        [&EBML_MAGIC[..], &[0x9F, 0x42, 0x86, 0x81, 0x01, 0x42, 0x82, 0x80 | doctype.len() as u8], doctype].concat()
+    }
+
+    fn riff(form: &[u8; 4]) -> Vec<u8> {
+        [&b"RIFF"[..], &[0x24, 0x00, 0x01, 0x00], form, b"LIST"].concat()
+    }
+
+    /// `count` transport stream packets of `packet_len` bytes, each with its
+    /// sync byte `offset` bytes in.
+    fn packets(count: usize, offset: usize, packet_len: usize) -> Vec<u8> {
+        let mut data = vec![0xFF; count * packet_len];
+        for i in 0..count {
+            data[i * packet_len + offset] = TS_SYNC_BYTE;
+        }
+        data
+    }
+
+    #[test]
+    fn identifies_containers() {
+        let cases: &[(&str, Vec<u8>, Container)] = &[
+            ("mp4", ftyp(b"isom"), Container::Mp4),
+            ("m4v", ftyp(b"M4V "), Container::Mp4),
+            ("quicktime", ftyp(b"qt  "), Container::QuickTime),
+            ("heic", ftyp(b"heic"), Container::Heif),
+            ("heif", ftyp(b"mif1"), Container::Heif),
+            ("avif", ftyp(b"avif"), Container::Heif),
+            ("mkv", ebml(b"matroska"), Container::Matroska),
+            ("webm", ebml(b"webm"), Container::WebM),
+            ("avi", riff(b"AVI "), Container::Avi),
+            ("wave", riff(b"WAVE"), Container::Wave),
+            ("ts", packets(3, 0, 188), Container::MpegTs),
+            ("m2ts", packets(3, 4, 192), Container::M2ts),
+            ("mpeg-ps", vec![0x00, 0x00, 0x01, 0xBA, 0x44, 0x00, 0x04, 0x00], Container::MpegPs),
+            ("flv", b"FLV\x01\x05\x00\x00\x00\x09".to_vec(), Container::Flv),
+            ("asf", [&ASF_GUID[..], &[0x00; 8]].concat(), Container::Asf),
+        ];
+        for (name, header, expected) in cases {
+            assert_eq!(identify(header), Some(*expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_near_misses() {
+        let cases: &[(&str, Vec<u8>)] = &[
+            ("empty", Vec::new()),
+            ("text", b"just some text, not a video".to_vec()),
+            ("ftyp at the wrong offset", [&b"ftyp"[..], b"isom", b"\x00\x00\x00\x00"].concat()),
+            ("truncated ftyp box", ftyp(b"isom")[..10].to_vec()),
+            ("partial EBML magic", EBML_MAGIC[..3].to_vec()),
+            ("RIFF of another form", riff(b"WEBP")),
+            ("truncated RIFF header", riff(b"AVI ")[..10].to_vec()),
+            ("FLV of another version", b"FLV\x02\x05\x00\x00\x00\x09".to_vec()),
+            ("ASF GUID off by one byte", [&ASF_GUID[..15], &[0x6D]].concat()),
+            ("MPEG-PS end code", vec![0x00, 0x00, 0x01, 0xB9]),
+            ("two TS packets", packets(2, 0, 188)),
+            ("TS sync bytes 187 apart", packets(3, 0, 187)),
+            ("two M2TS packets", packets(2, 4, 192)),
+            ("M2TS sync bytes at offset 0", packets(3, 0, 192)),
+        ];
+        for (name, header) in cases {
+            assert_eq!(identify(header), None, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Scratch directories for the unit tests.
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory for one test, removed again when it goes out of scope,
+/// whether the test passed or not.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// A directory below the temporary directory. `name` must be unique
+    /// among the tests, which run concurrently.
+    pub fn new(name: &str) -> Self {
+        Self::below(&env::temp_dir(), name)
+    }
+
+    /// A directory below `parent`, e.g. on a filesystem a test needs.
+    pub fn below(parent: &Path, name: &str) -> Self {
+        let dir = parent.join(format!("deduplicate-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}